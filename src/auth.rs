@@ -0,0 +1,132 @@
+use base64;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLevel {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Grant {
+    pub path_prefix: String,
+    pub level: AccessLevel,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub grants: Vec<Grant>,
+}
+
+static WRITE_METHODS: [&str; 7] = [
+    "PUT",
+    "DELETE",
+    "MKCOL",
+    "MOVE",
+    "COPY",
+    "PROPPATCH",
+    "LOCK",
+];
+
+pub fn is_write_method(method: &str) -> bool {
+    WRITE_METHODS.contains(&method)
+}
+
+/// Verifies a `<base64(json claims)>.<base64(hmac-sha256 signature)>` bearer
+/// token against `secret`, returning the decoded grants on success.
+pub fn verify_bearer_token(token: &str, secret: &str) -> Result<Claims, String> {
+    let (payload_b64, signature_b64) = token
+        .split_once('.')
+        .ok_or_else(|| "Malformed bearer token".to_string())?;
+
+    let signature =
+        base64::decode(signature_b64).map_err(|_| "Invalid token signature encoding".to_string())?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|_| "Invalid signing secret".to_string())?;
+    mac.update(payload_b64.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| "Token signature mismatch".to_string())?;
+
+    let payload =
+        base64::decode(payload_b64).map_err(|_| "Invalid token payload encoding".to_string())?;
+    serde_json::from_slice(&payload).map_err(|_| "Invalid token payload".to_string())
+}
+
+/// Resolves the access level granted to `path` by longest-prefix match over
+/// `claims.grants`, mirroring a capability model of scoped access entries.
+pub fn effective_level(claims: &Claims, path: &str) -> Option<AccessLevel> {
+    claims
+        .grants
+        .iter()
+        .filter(|grant| path.starts_with(&grant.path_prefix))
+        .max_by_key(|grant| grant.path_prefix.len())
+        .map(|grant| grant.level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(grants: Vec<(&str, AccessLevel)>) -> Claims {
+        Claims {
+            grants: grants
+                .into_iter()
+                .map(|(path_prefix, level)| Grant {
+                    path_prefix: path_prefix.to_string(),
+                    level,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn effective_level_picks_the_longest_matching_prefix() {
+        let claims = claims(vec![("/", AccessLevel::Read), ("/incoming/", AccessLevel::Write)]);
+        assert_eq!(
+            effective_level(&claims, "/incoming/file.txt"),
+            Some(AccessLevel::Write)
+        );
+        assert_eq!(
+            effective_level(&claims, "/other/file.txt"),
+            Some(AccessLevel::Read)
+        );
+    }
+
+    #[test]
+    fn effective_level_none_without_a_matching_grant() {
+        let claims = claims(vec![("/incoming/", AccessLevel::Write)]);
+        assert_eq!(effective_level(&claims, "/other/file.txt"), None);
+    }
+
+    fn sign(claims: &Claims, secret: &str) -> String {
+        let payload = serde_json::to_vec(claims).unwrap();
+        let payload_b64 = base64::encode(&payload);
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(payload_b64.as_bytes());
+        let signature_b64 = base64::encode(mac.finalize().into_bytes());
+        format!("{}.{}", payload_b64, signature_b64)
+    }
+
+    #[test]
+    fn verify_bearer_token_round_trips_a_signed_token() {
+        let claims = claims(vec![("/", AccessLevel::Write)]);
+        let token = sign(&claims, "secret");
+        let verified = verify_bearer_token(&token, "secret").unwrap();
+        assert_eq!(verified.grants.len(), 1);
+    }
+
+    #[test]
+    fn verify_bearer_token_rejects_a_wrong_secret() {
+        let token = sign(&claims(vec![]), "secret");
+        assert!(verify_bearer_token(&token, "wrong-secret").is_err());
+    }
+
+    #[test]
+    fn verify_bearer_token_rejects_a_malformed_token() {
+        assert!(verify_bearer_token("not-a-token", "secret").is_err());
+    }
+}
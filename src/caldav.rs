@@ -0,0 +1,184 @@
+use crate::xml::XMLNode;
+use icalendar::{Calendar, CalendarComponent, Component, DatePerhapsTime};
+
+/// A `C:time-range` from a `calendar-query` filter. A missing bound is
+/// treated as open-ended (`-infinity`/`+infinity`).
+pub struct TimeRange {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+}
+
+/// A `C:prop-filter` with a `C:text-match` child: substring-match `property`
+/// against its stored value.
+pub struct PropFilter {
+    pub property: String,
+    pub text: String,
+}
+
+/// The decoded `C:filter` from a `calendar-query` REPORT body: a `VEVENT`
+/// `comp-filter` nested inside a `VCALENDAR` one, carrying an optional
+/// time-range and/or prop-filter.
+#[derive(Default)]
+pub struct CalendarQueryFilter {
+    pub time_range: Option<TimeRange>,
+    pub prop_filter: Option<PropFilter>,
+}
+
+impl CalendarQueryFilter {
+    pub fn parse(report: &XMLNode) -> Option<CalendarQueryFilter> {
+        let filter = report.find_child("filter")?;
+        let vcalendar = filter.find_child("comp-filter")?;
+        let vevent = vcalendar.find_child("comp-filter")?;
+
+        let time_range = vevent.find_child("time-range").map(|node| TimeRange {
+            start: node.attr("start").and_then(|v| parse_ical_utc(v)),
+            end: node.attr("end").and_then(|v| parse_ical_utc(v)),
+        });
+
+        let prop_filter = vevent.find_child("prop-filter").and_then(|node| {
+            let property = node.attr("name")?.clone();
+            let text = node.find_child("text-match")?.value.clone()?;
+            Some(PropFilter { property, text })
+        });
+
+        Some(CalendarQueryFilter {
+            time_range,
+            prop_filter,
+        })
+    }
+
+    pub fn matches(&self, ics: &str) -> bool {
+        let calendar: Calendar = match ics.parse() {
+            Ok(c) => c,
+            Err(_) => return false,
+        };
+        calendar.components.iter().any(|component| {
+            let CalendarComponent::Event(event) = component else {
+                return false;
+            };
+            if let Some(time_range) = &self.time_range {
+                if !time_range.overlaps_event(event) {
+                    return false;
+                }
+            }
+            if let Some(prop_filter) = &self.prop_filter {
+                if !prop_filter.matches_event(event) {
+                    return false;
+                }
+            }
+            true
+        })
+    }
+}
+
+impl TimeRange {
+    /// `true` when `[DTSTART, DTEND)` overlaps `[start, end)`, per RFC 4791
+    /// §9.9; a missing `DTEND` on a date-time event is a zero-length instant.
+    fn overlaps_event(&self, event: &icalendar::Event) -> bool {
+        let Some(dtstart) = event.get_start().and_then(to_millis) else {
+            return false;
+        };
+        let dtend = event.get_end().and_then(to_millis).unwrap_or(dtstart);
+        let range_start = self.start.unwrap_or(i64::MIN);
+        let range_end = self.end.unwrap_or(i64::MAX);
+        dtstart < range_end && (dtend > range_start || (dtend == dtstart && dtstart >= range_start))
+    }
+}
+
+impl PropFilter {
+    fn matches_event(&self, event: &icalendar::Event) -> bool {
+        event
+            .property_value(&self.property)
+            .map_or(false, |v| v.contains(&self.text))
+    }
+}
+
+fn to_millis(date: DatePerhapsTime) -> Option<i64> {
+    match date {
+        DatePerhapsTime::DateTime(dt) => dt.try_into_utc().map(|dt| dt.timestamp_millis()),
+        DatePerhapsTime::Date(date) => {
+            Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp_millis())
+        }
+    }
+}
+
+/// Parses an iCalendar UTC timestamp like `20240516T000000Z` into Unix
+/// milliseconds.
+pub fn parse_ical_utc(value: &str) -> Option<i64> {
+    let value = value.strip_suffix('Z').unwrap_or(value);
+    if value.len() != 15 {
+        return None;
+    }
+    let year: i32 = value[0..4].parse().ok()?;
+    let month: u32 = value[4..6].parse().ok()?;
+    let day: u32 = value[6..8].parse().ok()?;
+    let hour: u32 = value[9..11].parse().ok()?;
+    let minute: u32 = value[11..13].parse().ok()?;
+    let second: u32 = value[13..15].parse().ok()?;
+    chrono::NaiveDate::from_ymd_opt(year, month, day)?
+        .and_hms_opt(hour, minute, second)
+        .map(|dt| dt.and_utc().timestamp_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ICS: &str = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:1\r\nSUMMARY:Standup\r\nDTSTART:20240516T090000Z\r\nDTEND:20240516T093000Z\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+
+    #[test]
+    fn parse_ical_utc_parses_a_valid_timestamp() {
+        let millis = parse_ical_utc("20240516T090000Z").unwrap();
+        let expected = chrono::NaiveDate::from_ymd_opt(2024, 5, 16)
+            .unwrap()
+            .and_hms_opt(9, 0, 0)
+            .unwrap()
+            .and_utc()
+            .timestamp_millis();
+        assert_eq!(millis, expected);
+    }
+
+    #[test]
+    fn parse_ical_utc_rejects_malformed_input() {
+        assert_eq!(parse_ical_utc("not-a-timestamp"), None);
+        assert_eq!(parse_ical_utc("20240516"), None);
+    }
+
+    fn filter_with_time_range(start: &str, end: &str) -> XMLNode {
+        let mut report = XMLNode::new("C:calendar-query".to_string(), None, None);
+        let filter = report.elem("C:filter".to_string(), None, None);
+        let vcalendar = filter.elem(
+            "C:comp-filter".to_string(),
+            Some(vec![("name".to_string(), "VCALENDAR".to_string())]),
+            None,
+        );
+        let vevent = vcalendar.elem(
+            "C:comp-filter".to_string(),
+            Some(vec![("name".to_string(), "VEVENT".to_string())]),
+            None,
+        );
+        vevent.elem(
+            "C:time-range".to_string(),
+            Some(vec![
+                ("start".to_string(), start.to_string()),
+                ("end".to_string(), end.to_string()),
+            ]),
+            None,
+        );
+        report
+    }
+
+    #[test]
+    fn calendar_query_filter_matches_an_overlapping_time_range() {
+        let report = filter_with_time_range("20240516T000000Z", "20240517T000000Z");
+        let filter = CalendarQueryFilter::parse(&report).unwrap();
+        assert!(filter.matches(ICS));
+    }
+
+    #[test]
+    fn calendar_query_filter_rejects_a_non_overlapping_time_range() {
+        let report = filter_with_time_range("20240517T000000Z", "20240518T000000Z");
+        let filter = CalendarQueryFilter::parse(&report).unwrap();
+        assert!(!filter.matches(ICS));
+    }
+}
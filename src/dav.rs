@@ -1,22 +1,28 @@
-use worker::ByteStream;
+use worker::{console_debug, ByteStream};
 
-use crate::r2::R2;
-use crate::values::{Depth, Overwrite, Range};
-use crate::xml::XMLNode;
+use crate::caldav::CalendarQueryFilter;
+use crate::lock::{parse_if_header, LockCheck, LockScope, LockStore};
+use crate::r2::{ConditionalOutcome, CopyOutcome, PatchOutcome, SyncChanges, R2};
+use crate::values::{Conditions, DavProperties, Depth, Overwrite, Range};
+use crate::xml::{local_name, XMLNode};
 use std::collections::HashMap;
 use std::option::Option;
+use uuid::Uuid;
 
 pub struct Dav {
     fs: R2,
+    locks: LockStore,
 }
 
 pub type DavResponse = (u16, HashMap<String, String>, String);
 pub type DavErrResponse = (u16, Option<HashMap<String, String>>, Option<String>);
 pub type DavStreamResponse = (u16, HashMap<String, String>, ByteStream);
+pub type DavBytesResponse = (u16, HashMap<String, String>, Vec<u8>);
 
 pub enum DavResponseType {
     DavResponse(Result<DavResponse, DavErrResponse>),
     DavStreamResponse(Result<DavStreamResponse, DavErrResponse>),
+    DavBytesResponse(Result<DavBytesResponse, DavErrResponse>),
 }
 
 impl From<Result<DavResponse, DavErrResponse>> for DavResponseType {
@@ -31,8 +37,33 @@ impl From<Result<DavStreamResponse, DavErrResponse>> for DavResponseType {
     }
 }
 
-static DAV_CLASS: &str = "1";
-static SUPPORT_METHODS: [&str; 9] = [
+impl From<Result<DavBytesResponse, DavErrResponse>> for DavResponseType {
+    fn from(value: Result<DavBytesResponse, DavErrResponse>) -> Self {
+        DavResponseType::DavBytesResponse(value)
+    }
+}
+
+/// A `GET`/`HEAD` on a resource resolves to either a streamed body (the
+/// common case, and the only one a single-range request needs) or a fully
+/// buffered one (a `multipart/byteranges` response, which must be built in
+/// memory to interleave per-part headers with object bytes).
+pub enum GetObjResponse {
+    Stream(DavStreamResponse),
+    Bytes(DavBytesResponse),
+}
+
+impl From<Result<GetObjResponse, DavErrResponse>> for DavResponseType {
+    fn from(value: Result<GetObjResponse, DavErrResponse>) -> Self {
+        match value {
+            Ok(GetObjResponse::Stream(r)) => DavResponseType::DavStreamResponse(Ok(r)),
+            Ok(GetObjResponse::Bytes(r)) => DavResponseType::DavBytesResponse(Ok(r)),
+            Err(e) => DavResponseType::DavStreamResponse(Err(e)),
+        }
+    }
+}
+
+static DAV_CLASS: &str = "1, 2, calendar-access";
+static SUPPORT_METHODS: [&str; 12] = [
     "OPTIONS",
     "PROPFIND",
     "PROPPATCH",
@@ -42,11 +73,139 @@ static SUPPORT_METHODS: [&str; 9] = [
     "PUT",
     "COPY",
     "MOVE",
+    "LOCK",
+    "UNLOCK",
+    "REPORT",
 ];
 
 impl Dav {
-    pub fn new(fs: R2) -> Dav {
-        Dav { fs }
+    pub fn new(fs: R2, locks: LockStore) -> Dav {
+        Dav { fs, locks }
+    }
+
+    /// Checks a write method's `If:` header against any active lock on `path`,
+    /// returning the error response to short-circuit with, if any.
+    async fn check_lock(&self, path: &str, if_header: Option<String>) -> Option<DavErrResponse> {
+        let submitted = if_header.as_deref().and_then(parse_if_header);
+        match self.locks.check(path, submitted.as_deref()).await {
+            LockCheck::Unlocked | LockCheck::Owned => None,
+            LockCheck::Mismatch => Some((423, None, Some("Locked".to_string()))),
+            LockCheck::Missing => Some((412, None, Some("Precondition Failed".to_string()))),
+        }
+    }
+
+    pub async fn handle_lock(
+        &self,
+        path: String,
+        depth: Depth,
+        timeout_header: Option<String>,
+        req_body: String,
+    ) -> Result<DavResponse, DavErrResponse> {
+        if let Some(existing) = self.locks.get(&path).await {
+            return Err((
+                423,
+                None,
+                Some(format!("Resource already locked by {}", existing.token)),
+            ));
+        }
+
+        let (owner, scope) = if req_body.len() > 0 {
+            match XMLNode::parse_xml(&req_body) {
+                Ok(xml) => {
+                    let lockscope = xml
+                        .find_child("lockscope")
+                        .and_then(|n| n.elements.first())
+                        .map(|n| LockScope::from(n.local_name().to_string()))
+                        .unwrap_or(LockScope::Exclusive);
+                    let owner = xml
+                        .find_child("owner")
+                        .and_then(|n| n.value.clone());
+                    (owner, lockscope)
+                }
+                Err(_) => return Err((415, None, None)),
+            }
+        } else {
+            (None, LockScope::Exclusive)
+        };
+
+        if scope == LockScope::Shared {
+            return Err((
+                409,
+                None,
+                Some("Shared locks are not supported".to_string()),
+            ));
+        }
+
+        let timeout = LockStore::parse_timeout(timeout_header);
+        let token = format!("opaquelocktoken:{}", Uuid::new_v4());
+        let depth_str = match depth {
+            Depth::Zero => "0".to_string(),
+            Depth::One => "1".to_string(),
+            Depth::Infinity => "infinity".to_string(),
+        };
+        let lock = match self
+            .locks
+            .create(path.clone(), token.clone(), owner, depth_str, timeout)
+            .await
+        {
+            Ok(lock) => lock,
+            Err(message) => return Err((500, None, Some(message))),
+        };
+
+        let mut headers = HashMap::new();
+        headers.insert(
+            "Content-Type".to_string(),
+            "application/xml; charset=utf-8".to_string(),
+        );
+        headers.insert("Lock-Token".to_string(), format!("<{}>", lock.token));
+
+        let mut prop = XMLNode::new(
+            "D:prop".to_string(),
+            Some(vec![("xmlns:D".to_string(), "DAV:".to_string())]),
+            None,
+        );
+        let activelock = prop
+            .elem("D:lockdiscovery".to_string(), None, None)
+            .elem("D:activelock".to_string(), None, None);
+        activelock
+            .elem("D:locktype".to_string(), None, None)
+            .elem("D:write".to_string(), None, None);
+        activelock
+            .elem("D:lockscope".to_string(), None, None)
+            .elem("D:exclusive".to_string(), None, None);
+        activelock.elem("D:depth".to_string(), None, Some(lock.depth.clone()));
+        activelock.elem(
+            "D:timeout".to_string(),
+            None,
+            Some(format!("Second-{}", timeout)),
+        );
+        activelock
+            .elem("D:locktoken".to_string(), None, None)
+            .elem("D:href".to_string(), None, Some(lock.token.clone()));
+
+        Ok((200, headers, prop.build()))
+    }
+
+    pub async fn handle_unlock(
+        &self,
+        path: String,
+        lock_token_header: Option<String>,
+    ) -> Result<DavResponse, DavErrResponse> {
+        let submitted = lock_token_header
+            .as_deref()
+            .map(|v| v.trim_start_matches('<').trim_end_matches('>').to_string());
+        match self.locks.get(&path).await {
+            None => Err((409, None, Some("No lock found on resource".to_string()))),
+            Some(lock) => match submitted {
+                Some(token) if token == lock.token => {
+                    match self.locks.remove(&path).await {
+                        Ok(()) => Ok((204, HashMap::new(), "".to_string())),
+                        Err(message) => Err((500, None, Some(message))),
+                    }
+                }
+                _ => Err((423, None, Some("Lock-Token does not match".to_string()))),
+            },
+        }
     }
 
     pub async fn handle_unsupport_method(&self) -> Result<DavResponse, DavErrResponse> {
@@ -69,7 +228,7 @@ impl Dav {
         depth: Depth,
         req_body: String,
     ) -> Result<DavResponse, DavErrResponse> {
-        let mut xml;
+        let xml;
         if req_body.len() > 0 {
             match XMLNode::parse_xml(&req_body) {
                 Ok(v) => xml = v,
@@ -78,6 +237,7 @@ impl Dav {
         } else {
             return Err((415, None, None));
         }
+        let mode = PropfindMode::parse(&xml);
 
         let mut headers = HashMap::new();
         headers.insert(
@@ -92,48 +252,22 @@ impl Dav {
                     Some(vec![("xmlns:D".to_string(), "DAV:".to_string())]),
                     None,
                 );
+                let mut namespaces = NamespaceRegistry::default();
                 match self.fs.list(path.clone()).await {
                     Ok(items) => {
                         for (href, properties) in items {
                             let response = multistatus.elem("D:response".to_string(), None, None);
-                            response.elem("D:href".to_string(), None, Some(href));
-                            let propstat = response.elem("D:propstat".to_string(), None, None);
-                            propstat.elem(
-                                "D:status".to_string(),
-                                None,
-                                Some("HTTP/1.1 200 OK".to_string()),
+                            write_propfind_response(
+                                response,
+                                href,
+                                &mode,
+                                available_properties(&properties, &HashMap::new(), &mut namespaces),
                             );
-                            let prop = propstat.elem("D:prop".to_string(), None, None);
-                            properties
-                                .creation_date
-                                .map(|v| prop.elem("D:creationdate".to_string(), None, Some(v)));
-                            properties
-                                .display_name
-                                .map(|v| prop.elem("D:displayname".to_string(), None, Some(v)));
-                            properties.get_content_language.map(|v| {
-                                prop.elem("D:getcontentlanguage".to_string(), None, Some(v))
-                            });
-                            properties.get_content_length.map(|v| {
-                                prop.elem(
-                                    "D:getcontentlength".to_string(),
-                                    None,
-                                    Some(v.to_string()),
-                                )
-                            });
-                            properties
-                                .get_content_type
-                                .map(|v| prop.elem("D:getcontenttype".to_string(), None, Some(v)));
-                            properties
-                                .get_etag
-                                .map(|v| prop.elem("D:getetag".to_string(), None, Some(v)));
-                            properties.get_last_modified.map(|v| {
-                                prop.elem(
-                                    "D:getlastmodified".to_string(),
-                                    None,
-                                    Some(v.to_string()),
-                                )
-                            });
                         }
+                        multistatus
+                            .attributes
+                            .get_or_insert_with(HashMap::new)
+                            .extend(namespaces.declarations());
 
                         Ok((207, headers, multistatus.build()))
                     }
@@ -146,42 +280,24 @@ impl Dav {
                     Some(vec![("xmlns:D".to_string(), "DAV:".to_string())]),
                     None,
                 );
-                match self.fs.get(path.clone()).await {
-                    Ok((href, properties, _, custom_metadata)) => {
+                let mut namespaces = NamespaceRegistry::default();
+                match self.fs.get(path.clone(), Conditions::default()).await {
+                    Ok(ConditionalOutcome::NotModified) => return Err((304, None, None)),
+                    Ok(ConditionalOutcome::PreconditionFailed) => {
+                        return Err((412, None, None))
+                    }
+                    Ok(ConditionalOutcome::Proceed((href, properties, custom_metadata))) => {
                         let response = multistatus.elem("D:response".to_string(), None, None);
-                        response.elem("D:href".to_string(), None, Some(href));
-                        let propstat = response.elem("D:propstat".to_string(), None, None);
-                        propstat.elem(
-                            "D:status".to_string(),
-                            None,
-                            Some("HTTP/1.1 200 OK".to_string()),
+                        write_propfind_response(
+                            response,
+                            href,
+                            &mode,
+                            available_properties(&properties, &custom_metadata, &mut namespaces),
                         );
-                        let prop = propstat.elem("D:prop".to_string(), None, None);
-                        properties
-                            .creation_date
-                            .map(|v| prop.elem("D:creationdate".to_string(), None, Some(v)));
-                        properties
-                            .display_name
-                            .map(|v| prop.elem("D:displayname".to_string(), None, Some(v)));
-                        properties
-                            .get_content_language
-                            .map(|v| prop.elem("D:getcontentlanguage".to_string(), None, Some(v)));
-                        properties.get_content_length.map(|v| {
-                            prop.elem("D:getcontentlength".to_string(), None, Some(v.to_string()))
-                        });
-                        properties
-                            .get_content_type
-                            .map(|v| prop.elem("D:getcontenttype".to_string(), None, Some(v)));
-                        properties
-                            .get_etag
-                            .map(|v| prop.elem("D:getetag".to_string(), None, Some(v)));
-                        properties.get_last_modified.map(|v| {
-                            prop.elem("D:getlastmodified".to_string(), None, Some(v.to_string()))
-                        });
-
-                        for (key, value) in custom_metadata {
-                            prop.elem(key, None, Some(value));
-                        }
+                        multistatus
+                            .attributes
+                            .get_or_insert_with(HashMap::new)
+                            .extend(namespaces.declarations());
 
                         Ok((207, (headers), (multistatus.build())))
                     }
@@ -189,15 +305,19 @@ impl Dav {
                         if !path.ends_with("/") {
                             return Err((404, None, None));
                         }
+                        let sync_token = self
+                            .fs
+                            .sync_since(path.clone(), 0)
+                            .await
+                            .ok()
+                            .map(|changes| encode_sync_token(changes.high_water_millis));
                         let response = multistatus.elem("D:response".to_string(), None, None);
-                        response.elem("D:href".to_string(), None, Some(path));
-                        let propstat = response.elem("D:propstat".to_string(), None, None);
-                        propstat.elem(
-                            "D:status".to_string(),
-                            None,
-                            Some("HTTP/1.1 200 OK".to_string()),
+                        write_propfind_response(
+                            response,
+                            path,
+                            &mode,
+                            vec![("D:sync-token".to_string(), sync_token)],
                         );
-                        propstat.elem("D:prop".to_string(), None, None);
 
                         Ok((207, (headers), (multistatus.build())))
                     }
@@ -210,29 +330,245 @@ impl Dav {
     pub async fn handle_proppatch(
         &self,
         path: String,
+        if_header: Option<String>,
         req_body: String,
     ) -> Result<DavResponse, DavErrResponse> {
+        if let Some(err) = self.check_lock(&path, if_header).await {
+            return Err(err);
+        }
+
         let mut headers = HashMap::new();
         headers.insert(
             "Content-Type".to_string(),
             "application/xml; charset=utf-8".to_string(),
         );
+
+        let xml = match XMLNode::parse_xml(&req_body) {
+            Ok(v) => v,
+            Err(_) => return Err((415, None, None)),
+        };
+
+        let mut updates: HashMap<String, Option<String>> = HashMap::new();
+        let mut names_by_key: HashMap<String, String> = HashMap::new();
+        if let Some(set) = xml.find_child("set") {
+            if let Some(prop) = set.find_child("prop") {
+                for property in &prop.elements {
+                    let key = encode_property_key(&property.name, &xml);
+                    updates.insert(key.clone(), property.value.clone());
+                    names_by_key.insert(key, property.name.clone());
+                }
+            }
+        }
+        if let Some(remove) = xml.find_child("remove") {
+            if let Some(prop) = remove.find_child("prop") {
+                for property in &prop.elements {
+                    let key = encode_property_key(&property.name, &xml);
+                    updates.insert(key.clone(), None);
+                    names_by_key.insert(key, property.name.clone());
+                }
+            }
+        }
+
         let mut multistatus = XMLNode::new(
             "D:multistatus".to_string(),
             Some(vec![("xmlns:D".to_string(), "DAV:".to_string())]),
             None,
         );
+        multistatus
+            .attributes
+            .get_or_insert_with(HashMap::new)
+            .extend(xmlns_declarations(&xml));
         let response = multistatus.elem("D:response".to_string(), None, None);
-        response.elem("D:href".to_string(), None, Some(path));
-        let propstat = response.elem("D:propstat".to_string(), None, None);
-        let prop = propstat.elem("D:prop".to_string(), None, None);
-        // TODO
-        propstat.elem(
-            "D:status".to_string(),
+        response.elem("D:href".to_string(), None, Some(path.clone()));
+
+        match self.fs.patch_custom_metadata(path, updates).await {
+            Ok(outcomes) => {
+                let mut by_status: HashMap<&str, Vec<String>> = HashMap::new();
+                for (key, outcome) in outcomes {
+                    let name = names_by_key.get(&key).cloned().unwrap_or(key);
+                    let status = match outcome {
+                        PatchOutcome::Applied => "HTTP/1.1 200 OK",
+                        PatchOutcome::TooLarge => "HTTP/1.1 507 Insufficient Storage",
+                    };
+                    by_status.entry(status).or_default().push(name);
+                }
+                for (status, names) in by_status {
+                    write_proppatch_propstat(response, names, status.to_string());
+                }
+            }
+            Err(message) => {
+                console_debug!("proppatch failed: {}", message);
+                let names = names_by_key.into_values().collect();
+                write_proppatch_propstat(response, names, "HTTP/1.1 409 Conflict".to_string());
+            }
+        }
+        Ok((207, headers, multistatus.build()))
+    }
+
+    /// Implements the CalDAV `REPORT` method (RFC 4791 §7): `calendar-query`
+    /// filters the collection by a `VEVENT` time-range/prop-filter, and
+    /// `calendar-multiget` resolves an explicit list of `D:href`s. Both
+    /// return each matched resource's raw `.ics` text as `C:calendar-data`.
+    pub async fn handle_report(
+        &self,
+        path: String,
+        req_body: String,
+    ) -> Result<DavResponse, DavErrResponse> {
+        let xml = match XMLNode::parse_xml(&req_body) {
+            Ok(v) => v,
+            Err(_) => return Err((415, None, None)),
+        };
+
+        if xml.local_name() == "sync-collection" {
+            return self.handle_sync_collection_report(path, &xml).await;
+        }
+
+        let hrefs: Vec<String> = match xml.local_name() {
+            "calendar-multiget" => xml
+                .find_children("href")
+                .filter_map(|h| h.value.clone())
+                .collect(),
+            "calendar-query" => {
+                let items = match self.fs.list(path).await {
+                    Ok(items) => items,
+                    Err(message) => return Err((404, None, Some(message))),
+                };
+                items.into_iter().map(|(href, _)| href).collect()
+            }
+            _ => return Err((403, None, Some("Unsupported REPORT type".to_string()))),
+        };
+
+        let mut headers = HashMap::new();
+        headers.insert(
+            "Content-Type".to_string(),
+            "application/xml; charset=utf-8".to_string(),
+        );
+        let mut multistatus = XMLNode::new(
+            "D:multistatus".to_string(),
+            Some(vec![
+                ("xmlns:D".to_string(), "DAV:".to_string()),
+                (
+                    "xmlns:C".to_string(),
+                    "urn:ietf:params:xml:ns:caldav".to_string(),
+                ),
+            ]),
             None,
-            Some("HTTP/1.1 200 OK".to_string()),
         );
-        Ok((207, HashMap::new(), multistatus.build()))
+
+        let filter = match xml.local_name() {
+            "calendar-query" => CalendarQueryFilter::parse(&xml),
+            _ => None,
+        };
+
+        for href in hrefs {
+            let ics = match self.fs.read_to_string(href.clone()).await {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            if let Some(filter) = &filter {
+                if !filter.matches(&ics) {
+                    continue;
+                }
+            }
+            let response = multistatus.elem("D:response".to_string(), None, None);
+            response.elem("D:href".to_string(), None, Some(href));
+            let propstat = response.elem("D:propstat".to_string(), None, None);
+            propstat.elem(
+                "D:status".to_string(),
+                None,
+                Some("HTTP/1.1 200 OK".to_string()),
+            );
+            let prop = propstat.elem("D:prop".to_string(), None, None);
+            prop.elem("C:calendar-data".to_string(), None, Some(ics));
+        }
+
+        Ok((207, headers, multistatus.build()))
+    }
+
+    /// Implements the RFC 6578 `sync-collection` REPORT: replies with what
+    /// changed under `path` since the client's `D:sync-token` (absent/empty
+    /// means "everything"), followed by a fresh token to resume from next
+    /// time.
+    async fn handle_sync_collection_report(
+        &self,
+        path: String,
+        report: &XMLNode,
+    ) -> Result<DavResponse, DavErrResponse> {
+        let submitted_token = report
+            .find_child("sync-token")
+            .and_then(|n| n.value.clone())
+            .filter(|v| !v.is_empty());
+        let since_millis = match submitted_token {
+            None => 0,
+            Some(token) => match parse_sync_token(&token) {
+                Some(millis) => millis,
+                None => {
+                    return Err((
+                        403,
+                        None,
+                        Some(
+                            "<D:error xmlns:D=\"DAV:\"><D:valid-sync-token/></D:error>"
+                                .to_string(),
+                        ),
+                    ))
+                }
+            },
+        };
+
+        let changes: SyncChanges = match self.fs.sync_since(path, since_millis).await {
+            Ok(changes) => changes,
+            Err(message) => return Err((404, None, Some(message))),
+        };
+
+        let mut headers = HashMap::new();
+        headers.insert(
+            "Content-Type".to_string(),
+            "application/xml; charset=utf-8".to_string(),
+        );
+        let mut multistatus = XMLNode::new(
+            "D:multistatus".to_string(),
+            Some(vec![("xmlns:D".to_string(), "DAV:".to_string())]),
+            None,
+        );
+
+        for (href, properties) in changes.changed {
+            let response = multistatus.elem("D:response".to_string(), None, None);
+            response.elem("D:href".to_string(), None, Some(href));
+            let propstat = response.elem("D:propstat".to_string(), None, None);
+            propstat.elem(
+                "D:status".to_string(),
+                None,
+                Some("HTTP/1.1 200 OK".to_string()),
+            );
+            let prop = propstat.elem("D:prop".to_string(), None, None);
+            properties
+                .get_etag
+                .map(|v| prop.elem("D:getetag".to_string(), None, Some(v)));
+            properties.get_last_modified.map(|v| {
+                prop.elem("D:getlastmodified".to_string(), None, Some(v.to_string()))
+            });
+            properties.get_content_length.map(|v| {
+                prop.elem("D:getcontentlength".to_string(), None, Some(v.to_string()))
+            });
+        }
+
+        for href in changes.deleted {
+            let response = multistatus.elem("D:response".to_string(), None, None);
+            response.elem("D:href".to_string(), None, Some(href));
+            response.elem(
+                "D:status".to_string(),
+                None,
+                Some("HTTP/1.1 404 Not Found".to_string()),
+            );
+        }
+
+        multistatus.elem(
+            "D:sync-token".to_string(),
+            None,
+            Some(encode_sync_token(changes.high_water_millis)),
+        );
+
+        Ok((207, headers, multistatus.build()))
     }
 
     pub async fn handle_mkcol(
@@ -252,53 +588,83 @@ impl Dav {
         &self,
         path: String,
         range: Range,
-    ) -> Result<DavStreamResponse, DavErrResponse> {
-        match self.fs.download(path, range.clone()).await {
-            Ok((properties, response_headers, stream)) => {
+        conditions: Conditions,
+    ) -> Result<GetObjResponse, DavErrResponse> {
+        use crate::r2::DownloadOutcome;
+
+        match self.fs.download(path, range, conditions).await {
+            Ok(ConditionalOutcome::NotModified) => Err((304, None, None)),
+            Ok(ConditionalOutcome::PreconditionFailed) => Err((412, None, None)),
+            Ok(ConditionalOutcome::Proceed((properties, outcome))) => {
                 let mut headers: HashMap<String, String> = HashMap::new();
                 headers.insert("Accept-Ranges".to_string(), "bytes".to_string());
-                headers.insert(
-                    "Content-Type".to_string(),
-                    properties
-                        .get_content_type
-                        .map_or("application/octet-stream".to_string(), |v| v),
-                );
-                headers.insert(
-                    "Content-Length".to_string(),
-                    properties
-                        .get_content_length
-                        .map_or("0".to_string(), |v| v.to_string()),
-                );
+                let content_type = properties
+                    .get_content_type
+                    .clone()
+                    .unwrap_or("application/octet-stream".to_string());
+                let content_length = properties.get_content_length.unwrap_or(0);
                 properties
                     .get_etag
+                    .clone()
                     .map(|v| headers.insert("ETag".to_string(), v));
                 properties
                     .get_last_modified
+                    .clone()
                     .map(|v| headers.insert("Last-Modified".to_string(), v));
-                response_headers
-                    .cache_control
-                    .map(|v| headers.insert("Cache-Control".to_string(), v));
-                response_headers
-                    .cache_expiry
-                    .map(|v| headers.insert("Expires".to_string(), v.to_string()));
-                response_headers
-                    .content_disposition
-                    .map(|v| headers.insert("Content-Disposition".to_string(), v));
-                response_headers
-                    .content_encoding
-                    .map(|v| headers.insert("Content-Encoding".to_string(), v));
-                match (range.start, range.end) {
-                    (Some(start), Some(end)) => {
+
+                match outcome {
+                    DownloadOutcome::Unsatisfiable => {
                         headers.insert(
                             "Content-Range".to_string(),
-                            format!("bytes {}-{}/{}", start, end, end - start + 1),
+                            format!("bytes */{}", content_length),
                         );
-                        Ok((206, (headers), stream))
+                        Err((416, Some(headers), None))
+                    }
+                    DownloadOutcome::Full(stream) => {
+                        headers.insert("Content-Type".to_string(), content_type);
+                        headers.insert("Content-Length".to_string(), content_length.to_string());
+                        Ok(GetObjResponse::Stream((200, headers, stream)))
+                    }
+                    DownloadOutcome::Single { start, end, stream } => {
+                        headers.insert("Content-Type".to_string(), content_type);
+                        headers.insert(
+                            "Content-Length".to_string(),
+                            (end - start + 1).to_string(),
+                        );
+                        headers.insert(
+                            "Content-Range".to_string(),
+                            format!("bytes {}-{}/{}", start, end, content_length),
+                        );
+                        Ok(GetObjResponse::Stream((206, headers, stream)))
+                    }
+                    DownloadOutcome::Multipart { boundary, parts } => {
+                        let mut body = Vec::new();
+                        for part in &parts {
+                            body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+                            body.extend_from_slice(
+                                format!("Content-Type: {}\r\n", content_type).as_bytes(),
+                            );
+                            body.extend_from_slice(
+                                format!(
+                                    "Content-Range: bytes {}-{}/{}\r\n\r\n",
+                                    part.start, part.end, content_length
+                                )
+                                .as_bytes(),
+                            );
+                            body.extend_from_slice(&part.body);
+                            body.extend_from_slice(b"\r\n");
+                        }
+                        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+                        headers.insert(
+                            "Content-Type".to_string(),
+                            format!("multipart/byteranges; boundary={}", boundary),
+                        );
+                        headers.insert("Content-Length".to_string(), body.len().to_string());
+                        Ok(GetObjResponse::Bytes((206, headers, body)))
                     }
-                    _ => Ok((200, (headers), stream)),
                 }
             }
-            Err(message) => return Err((404, None, Some(message))),
+            Err(message) => Err((404, None, Some(message))),
         }
     }
 
@@ -336,9 +702,15 @@ impl Dav {
         &self,
         path: String,
         range: Range,
+        conditions: Conditions,
     ) -> Result<DavResponse, DavErrResponse> {
-        match self.handle_get_obj(path, range).await {
-            Ok((status_code, headers, _)) => Ok((status_code, headers, "".to_string())),
+        match self.handle_get_obj(path, range, conditions).await {
+            Ok(GetObjResponse::Stream((status_code, headers, _))) => {
+                Ok((status_code, headers, "".to_string()))
+            }
+            Ok(GetObjResponse::Bytes((status_code, headers, _))) => {
+                Ok((status_code, headers, "".to_string()))
+            }
             Err(e) => Err(e),
         }
     }
@@ -350,9 +722,22 @@ impl Dav {
         }
     }
 
-    pub async fn handle_delete(&self, path: String) -> Result<DavResponse, DavErrResponse> {
-        match self.fs.delete(path).await {
-            Ok(()) => Ok((204, HashMap::new(), "".to_string())),
+    pub async fn handle_delete(
+        &self,
+        path: String,
+        if_header: Option<String>,
+    ) -> Result<DavResponse, DavErrResponse> {
+        if let Some(err) = self.check_lock(&path, if_header).await {
+            return Err(err);
+        }
+
+        match self.fs.delete(path.clone()).await {
+            Ok(()) => {
+                if let Err(message) = self.fs.record_tombstone(path).await {
+                    console_debug!("failed to record tombstone: {}", message);
+                }
+                Ok((204, HashMap::new(), "".to_string()))
+            }
             Err(error) => Err((400, None, Some(error.to_string()))),
         }
     }
@@ -360,15 +745,33 @@ impl Dav {
     pub async fn handle_put(
         &self,
         path: String,
+        if_header: Option<String>,
         stream: ByteStream,
         content_length: u64,
+        conditions: Conditions,
     ) -> Result<DavResponse, DavErrResponse> {
         if path.ends_with("/") {
             return Err((405, None, None));
         }
-        match self.fs.put(path, stream, content_length).await {
-            Ok(properties) => {
-                println!("{:?}", properties);
+        if let Some(err) = self.check_lock(&path, if_header).await {
+            return Err(err);
+        }
+
+        if content_length >= crate::r2::MULTIPART_THRESHOLD {
+            return match self.fs.put_multipart(path, stream).await {
+                Ok(properties) => {
+                    console_debug!("{:?}", properties);
+                    Ok((201, HashMap::new(), "".to_string()))
+                }
+                Err(error) => Err((400, None, Some(error))),
+            };
+        }
+
+        match self.fs.put(path, stream, content_length, conditions).await {
+            Ok(ConditionalOutcome::PreconditionFailed) => Err((412, None, None)),
+            Ok(ConditionalOutcome::NotModified) => Err((412, None, None)),
+            Ok(ConditionalOutcome::Proceed(properties)) => {
+                console_debug!("{:?}", properties);
                 Ok((201, HashMap::new(), "".to_string()))
             }
             Err(error) => Err((400, None, Some(error.to_string()))),
@@ -381,15 +784,31 @@ impl Dav {
         destination: String,
         depth: Depth,
         overwrite: Overwrite,
+        if_header: Option<String>,
     ) -> Result<DavResponse, DavErrResponse> {
+        if let Some(err) = self.check_lock(&destination, if_header).await {
+            return Err(err);
+        }
+        if destination.is_empty() {
+            return Err((400, None, Some("Missing Destination header".to_string())));
+        }
+        let overwrite = overwrite == Overwrite::True;
+
         if path.ends_with("/") {
             match depth {
-                Depth::Zero => Err((400, None, Some("Unsupported copy collection".to_string()))),
-                Depth::Infinity => Ok((200, HashMap::new(), "".to_string())),
+                Depth::Infinity => {
+                    self.copy_or_move_collection(path, destination, overwrite, false)
+                        .await
+                }
                 _ => Err((400, None, Some("Unsupported copy depth".to_string()))),
             }
         } else {
-            Err((400, None, Some("Unsupported copy resource".to_string())))
+            match self.fs.copy_object(path, destination, overwrite).await {
+                Ok(CopyOutcome::Created) => Ok((201, HashMap::new(), "".to_string())),
+                Ok(CopyOutcome::Overwritten) => Ok((204, HashMap::new(), "".to_string())),
+                Ok(CopyOutcome::Conflict) => Err((412, None, None)),
+                Err(message) => Err((404, None, Some(message))),
+            }
         }
     }
 
@@ -399,15 +818,382 @@ impl Dav {
         destination: String,
         depth: Depth,
         overwrite: Overwrite,
+        if_header: Option<String>,
     ) -> Result<DavResponse, DavErrResponse> {
+        if let Some(err) = self.check_lock(&path, if_header.clone()).await {
+            return Err(err);
+        }
+        if let Some(err) = self.check_lock(&destination, if_header).await {
+            return Err(err);
+        }
+        if destination.is_empty() {
+            return Err((400, None, Some("Missing Destination header".to_string())));
+        }
+        let overwrite = overwrite == Overwrite::True;
+
         if path.ends_with("/") {
             match depth {
-                Depth::Zero => Err((400, None, Some("Unsupported move collection".to_string()))),
-                Depth::Infinity => Ok((200, HashMap::new(), "".to_string())),
+                Depth::Infinity => {
+                    self.copy_or_move_collection(path, destination, overwrite, true)
+                        .await
+                }
                 _ => Err((400, None, Some("Unsupported move depth".to_string()))),
             }
         } else {
-            Err((400, None, Some("Unsupported move resource".to_string())))
+            match self.fs.copy_object(path.clone(), destination, overwrite).await {
+                Ok(CopyOutcome::Conflict) => Err((412, None, None)),
+                Ok(outcome) => {
+                    if let Err(message) = self.fs.delete(path).await {
+                        return Err((502, None, Some(message)));
+                    }
+                    Ok(match outcome {
+                        CopyOutcome::Created => (201, HashMap::new(), "".to_string()),
+                        CopyOutcome::Overwritten => (204, HashMap::new(), "".to_string()),
+                        CopyOutcome::Conflict => unreachable!("handled above"),
+                    })
+                }
+                Err(message) => Err((404, None, Some(message))),
+            }
+        }
+    }
+
+    /// Shared `Depth: infinity` COPY/MOVE body: copies every resource under
+    /// `path` to the matching key under `destination`, deleting the
+    /// original on success when `delete_source` is set (i.e. for MOVE).
+    /// Per-resource failures are aggregated into a `207 D:multistatus`
+    /// instead of failing the whole request.
+    async fn copy_or_move_collection(
+        &self,
+        path: String,
+        destination: String,
+        overwrite: bool,
+        delete_source: bool,
+    ) -> Result<DavResponse, DavErrResponse> {
+        let items = match self.fs.list(path.clone()).await {
+            Ok(items) => items,
+            Err(message) => return Err((404, None, Some(message))),
+        };
+
+        let mut failures: Vec<(String, u16)> = Vec::new();
+        for (href, _) in items {
+            let suffix = href.strip_prefix(path.as_str()).unwrap_or(href.as_str());
+            let dest_href = format!("{}{}", destination, suffix);
+            match self.fs.copy_object(href.clone(), dest_href, overwrite).await {
+                Ok(CopyOutcome::Conflict) => failures.push((href, 412)),
+                Ok(_) if delete_source => {
+                    if let Err(message) = self.fs.delete(href.clone()).await {
+                        console_debug!("failed to delete {} after move: {}", href, message);
+                        failures.push((href, 502));
+                    }
+                }
+                Ok(_) => {}
+                Err(message) => {
+                    console_debug!("failed to copy {}: {}", href, message);
+                    failures.push((href, 502));
+                }
+            }
+        }
+
+        if failures.is_empty() {
+            return Ok((204, HashMap::new(), "".to_string()));
+        }
+
+        let mut headers = HashMap::new();
+        headers.insert(
+            "Content-Type".to_string(),
+            "application/xml; charset=utf-8".to_string(),
+        );
+        let mut multistatus = XMLNode::new(
+            "D:multistatus".to_string(),
+            Some(vec![("xmlns:D".to_string(), "DAV:".to_string())]),
+            None,
+        );
+        for (href, status) in failures {
+            let response = multistatus.elem("D:response".to_string(), None, None);
+            response.elem("D:href".to_string(), None, Some(href));
+            response.elem("D:status".to_string(), None, Some(status_line(status)));
+        }
+        Ok((207, headers, multistatus.build()))
+    }
+}
+
+fn status_line(status: u16) -> String {
+    let reason = match status {
+        412 => "Precondition Failed",
+        502 => "Bad Gateway",
+        _ => "Error",
+    };
+    format!("HTTP/1.1 {} {}", status, reason)
+}
+
+/// Which properties a `PROPFIND` body asked for (RFC 4918 §14.20): the
+/// default `D:allprop` behavior, `D:propname` (names only, no values), or an
+/// explicit `D:prop` list of (namespace-stripped) property names.
+enum PropfindMode {
+    AllProp,
+    PropName,
+    Prop(Vec<String>),
+}
+
+impl PropfindMode {
+    fn parse(propfind: &XMLNode) -> PropfindMode {
+        if propfind.find_child("propname").is_some() {
+            return PropfindMode::PropName;
+        }
+        if let Some(prop) = propfind.find_child("prop") {
+            return PropfindMode::Prop(
+                prop.elements.iter().map(|e| e.local_name().to_string()).collect(),
+            );
+        }
+        PropfindMode::AllProp
+    }
+}
+
+/// The DAV-standard properties plus any dead properties from custom
+/// metadata, as (qualified name, value) pairs ready for `write_propfind_response`.
+/// Any dead property outside the `DAV:` namespace gets its prefix assigned
+/// (or reused) from `namespaces`, which the caller must declare on the
+/// response root via `NamespaceRegistry::declarations`.
+fn available_properties(
+    properties: &DavProperties,
+    custom_metadata: &HashMap<String, String>,
+    namespaces: &mut NamespaceRegistry,
+) -> Vec<(String, Option<String>)> {
+    let mut available = vec![
+        ("D:creationdate".to_string(), properties.creation_date.clone()),
+        ("D:displayname".to_string(), properties.display_name.clone()),
+        (
+            "D:getcontentlanguage".to_string(),
+            properties.get_content_language.clone(),
+        ),
+        (
+            "D:getcontentlength".to_string(),
+            properties.get_content_length.map(|v| v.to_string()),
+        ),
+        (
+            "D:getcontenttype".to_string(),
+            properties.get_content_type.clone(),
+        ),
+        ("D:getetag".to_string(), properties.get_etag.clone()),
+        (
+            "D:getlastmodified".to_string(),
+            properties.get_last_modified.clone(),
+        ),
+    ];
+    for (key, value) in custom_metadata {
+        let (namespace, local) = decode_property_key(key);
+        let prefix = namespaces.prefix_for(&namespace);
+        available.push((format!("{}:{}", prefix, local), Some(value.clone())));
+    }
+    available
+}
+
+/// Assigns a stable synthetic prefix (`ns0`, `ns1`, ...) to each distinct
+/// non-`DAV:` namespace URI seen while building a PROPFIND response, since
+/// by the time a resource is read back we no longer have the prefix letter
+/// its PROPPATCH request happened to use — only the resolved namespace
+/// `encode_property_key` stored. The `DAV:` namespace always gets `D`.
+#[derive(Default)]
+struct NamespaceRegistry {
+    prefixes: HashMap<String, String>,
+}
+
+impl NamespaceRegistry {
+    fn prefix_for(&mut self, namespace: &str) -> String {
+        if namespace == "DAV:" {
+            return "D".to_string();
         }
+        if let Some(existing) = self.prefixes.get(namespace) {
+            return existing.clone();
+        }
+        let prefix = format!("ns{}", self.prefixes.len());
+        self.prefixes.insert(namespace.to_string(), prefix.clone());
+        prefix
+    }
+
+    /// `xmlns:*` declarations for every namespace assigned a prefix so far.
+    fn declarations(&self) -> Vec<(String, String)> {
+        self.prefixes
+            .iter()
+            .map(|(namespace, prefix)| (format!("xmlns:{}", prefix), namespace.clone()))
+            .collect()
+    }
+}
+
+/// Looks up `xmlns:{prefix}` on `root`'s own attributes, e.g. `Z` against
+/// `xmlns:Z="urn:x"` -> `Some("urn:x")`.
+fn resolve_namespace(prefix: &str, root: &XMLNode) -> Option<String> {
+    root.attributes
+        .as_ref()?
+        .get(&format!("xmlns:{}", prefix))
+        .cloned()
+}
+
+/// Encodes a (possibly prefixed) PROPPATCH property name as an R2 custom
+/// metadata key. The prefix is resolved against `root`'s own `xmlns:*`
+/// declarations so the *namespace URI* is what's stored, e.g. `Z:color`
+/// with `xmlns:Z="urn:x"` -> `dav:urn:x:color` — storing the raw prefix
+/// letter instead would be meaningless once a later, unrelated PROPFIND
+/// reads it back. An undeclared prefix falls back to itself; an unprefixed
+/// name falls back to the `DAV:` namespace, e.g. `color` -> `dav:DAV::color`.
+fn encode_property_key(name: &str, root: &XMLNode) -> String {
+    match name.split_once(':') {
+        Some((prefix, local)) => {
+            let namespace = resolve_namespace(prefix, root).unwrap_or_else(|| prefix.to_string());
+            format!("dav:{}:{}", namespace, local)
+        }
+        None => format!("dav:DAV::{}", name),
+    }
+}
+
+/// Reverses `encode_property_key` into (namespace, local name). Keys that
+/// don't carry our `dav:` prefix (e.g. metadata from before this encoding
+/// existed) are treated as bare `DAV:`-namespace names.
+fn decode_property_key(key: &str) -> (String, String) {
+    match key.strip_prefix("dav:") {
+        Some(rest) => match rest.rsplit_once(':') {
+            Some((namespace, local)) => (namespace.to_string(), local.to_string()),
+            None => ("DAV:".to_string(), rest.to_string()),
+        },
+        None => ("DAV:".to_string(), key.to_string()),
+    }
+}
+
+/// Collects `xmlns:*` declarations straight off a request's root element,
+/// for carrying forward into a response that echoes back the client's own
+/// (already-prefixed) property names verbatim — e.g. PROPPATCH's
+/// confirmation body, which never invents new prefixes of its own. `D` is
+/// excluded since every response declares its own canonical `xmlns:D`.
+fn xmlns_declarations(root: &XMLNode) -> Vec<(String, String)> {
+    root.attributes
+        .as_ref()
+        .map(|attrs| {
+            attrs
+                .iter()
+                .filter(|(key, _)| key.starts_with("xmlns:") && key.as_str() != "xmlns:D")
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Writes one `D:propstat` grouping `names` under a single `D:status`, for
+/// PROPPATCH's per-outcome response groups.
+fn write_proppatch_propstat(response: &mut XMLNode, names: Vec<String>, status: String) {
+    let propstat = response.elem("D:propstat".to_string(), None, None);
+    let prop = propstat.elem("D:prop".to_string(), None, None);
+    for name in names {
+        prop.elem(name, None, None);
+    }
+    propstat.elem("D:status".to_string(), None, Some(status));
+}
+
+/// Writes one `D:response` body (the `D:href` plus its `propstat`(s)) per
+/// `mode`: `allprop`/`propname` emit everything available, while `prop`
+/// splits requested names into a 200 `propstat` for the ones found and a
+/// 404 `propstat` for the ones that aren't.
+fn write_propfind_response(
+    response: &mut XMLNode,
+    href: String,
+    mode: &PropfindMode,
+    available: Vec<(String, Option<String>)>,
+) {
+    response.elem("D:href".to_string(), None, Some(href));
+    match mode {
+        PropfindMode::AllProp => {
+            let propstat = response.elem("D:propstat".to_string(), None, None);
+            let prop = propstat.elem("D:prop".to_string(), None, None);
+            for (name, value) in available {
+                if let Some(value) = value {
+                    prop.elem(name, None, Some(value));
+                }
+            }
+            propstat.elem(
+                "D:status".to_string(),
+                None,
+                Some("HTTP/1.1 200 OK".to_string()),
+            );
+        }
+        PropfindMode::PropName => {
+            let propstat = response.elem("D:propstat".to_string(), None, None);
+            let prop = propstat.elem("D:prop".to_string(), None, None);
+            for (name, _) in available {
+                prop.elem(name, None, None);
+            }
+            propstat.elem(
+                "D:status".to_string(),
+                None,
+                Some("HTTP/1.1 200 OK".to_string()),
+            );
+        }
+        PropfindMode::Prop(names) => {
+            let mut found = Vec::new();
+            let mut missing = Vec::new();
+            for name in names {
+                match available
+                    .iter()
+                    .find(|(k, v)| local_name(k) == name && v.is_some())
+                {
+                    Some((k, v)) => found.push((k.clone(), v.clone().unwrap())),
+                    None => missing.push(name.clone()),
+                }
+            }
+            if !found.is_empty() {
+                let propstat = response.elem("D:propstat".to_string(), None, None);
+                let prop = propstat.elem("D:prop".to_string(), None, None);
+                for (name, value) in found {
+                    prop.elem(name, None, Some(value));
+                }
+                propstat.elem(
+                    "D:status".to_string(),
+                    None,
+                    Some("HTTP/1.1 200 OK".to_string()),
+                );
+            }
+            if !missing.is_empty() {
+                let propstat = response.elem("D:propstat".to_string(), None, None);
+                let prop = propstat.elem("D:prop".to_string(), None, None);
+                for name in missing {
+                    prop.elem(name, None, None);
+                }
+                propstat.elem(
+                    "D:status".to_string(),
+                    None,
+                    Some("HTTP/1.1 404 Not Found".to_string()),
+                );
+            }
+        }
+    }
+}
+
+/// Encodes a sync high-water mark (millis since epoch) as the opaque
+/// `sync-token` clients are expected to echo back verbatim.
+fn encode_sync_token(high_water_millis: i64) -> String {
+    format!("http://r2-webdav/sync/{}", high_water_millis)
+}
+
+/// Parses a `sync-token` minted by `encode_sync_token`, returning `None`
+/// for anything else so the caller can reply `D:valid-sync-token`.
+fn parse_sync_token(token: &str) -> Option<i64> {
+    token
+        .strip_prefix("http://r2-webdav/sync/")?
+        .parse::<i64>()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_token_round_trips_a_high_water_mark() {
+        let token = encode_sync_token(1_700_000_000_000);
+        assert_eq!(parse_sync_token(&token), Some(1_700_000_000_000));
+    }
+
+    #[test]
+    fn parse_sync_token_rejects_a_foreign_token() {
+        assert_eq!(parse_sync_token("not-a-sync-token"), None);
+        assert_eq!(parse_sync_token("http://r2-webdav/sync/not-a-number"), None);
     }
 }
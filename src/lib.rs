@@ -1,11 +1,15 @@
 use crate::values::Depth;
+use auth::{effective_level, is_write_method, verify_bearer_token, AccessLevel, Claims};
 use base64;
-use dav::{DavErrResponse, DavResponse, DavResponseType, DavStreamResponse};
+use dav::{DavBytesResponse, DavErrResponse, DavResponse, DavResponseType, DavStreamResponse};
 use r2::R2;
-use values::Overwrite;
+use values::{Conditions, Overwrite};
 use worker::*;
 
+mod auth;
+mod caldav;
 mod dav;
+mod lock;
 mod r2;
 mod values;
 mod xml;
@@ -16,19 +20,32 @@ async fn main(mut req: Request, env: Env, _: Context) -> Result<Response> {
     let password = env.var("PASSWORD").unwrap().to_string();
     let protocol = env.var("PROTOCOL").unwrap().to_string();
     let bucket_name = env.var("BUCKET_NAME").unwrap().to_string();
+    let bearer_secret = env.secret("BEARER_SECRET").ok().map(|v| v.to_string());
 
-    if let Some(r) = basic_authorization(
+    let destination = match req.inner().method().as_str() {
+        "COPY" | "MOVE" => Some(parse_destination(&req)).filter(|v| !v.is_empty()),
+        _ => None,
+    };
+
+    if let Some(r) = authorize(
         req.headers().get("authorization").unwrap(),
         username,
         password,
+        bearer_secret,
+        req.inner().method().as_str(),
+        &req.path(),
+        destination.as_deref(),
     ) {
         return r;
     }
 
-    let dav = dav::Dav::new(match protocol.as_str() {
-        "r2" => R2::new(env.bucket(bucket_name.as_str()).unwrap()),
-        _ => panic!("PROTOCOL {} not supported", protocol),
-    });
+    let dav = dav::Dav::new(
+        match protocol.as_str() {
+            "r2" => R2::new(env.bucket(bucket_name.as_str()).unwrap()),
+            _ => panic!("PROTOCOL {} not supported", protocol),
+        },
+        lock::LockStore::new(env.kv("LOCKS").unwrap()),
+    );
 
     let mut response = match match req.inner().method().as_str() {
         "PROPFIND" => {
@@ -41,15 +58,38 @@ async fn main(mut req: Request, env: Env, _: Context) -> Result<Response> {
         "PROPPATCH" => {
             let request_body = req.text().await?;
             console_debug!("request_body {:?}", request_body);
-            dav.handle_proppatch(req.path(), request_body).await.into()
+            dav.handle_proppatch(req.path(), parse_if(&req), request_body)
+                .await
+                .into()
         }
         "OPTIONS" => dav.handle_options().await.into(),
+        "LOCK" => {
+            let request_body = req.text().await?;
+            console_debug!("request_body {:?}", request_body);
+            dav.handle_lock(
+                req.path(),
+                parse_depth(&req),
+                req.headers().get("timeout").unwrap(),
+                request_body,
+            )
+            .await
+            .into()
+        }
+        "UNLOCK" => dav
+            .handle_unlock(req.path(), req.headers().get("lock-token").unwrap())
+            .await
+            .into(),
         "MKCOL" => dav.handle_mkcol(req.path(), req.text().await?).await.into(),
+        "REPORT" => {
+            let request_body = req.text().await?;
+            console_debug!("request_body {:?}", request_body);
+            dav.handle_report(req.path(), request_body).await.into()
+        }
         "GET" => {
             if req.path().ends_with("/") {
                 dav.handle_get_dir(req.path()).await.into()
             } else {
-                dav.handle_get_obj(req.path(), parse_range(&req))
+                dav.handle_get_obj(req.path(), parse_range(&req), parse_conditions(&req))
                     .await
                     .into()
             }
@@ -58,20 +98,22 @@ async fn main(mut req: Request, env: Env, _: Context) -> Result<Response> {
             if req.path().ends_with("/") {
                 dav.handle_head_dir(req.path()).await.into()
             } else {
-                dav.handle_head_obj(req.path(), parse_range(&req))
+                dav.handle_head_obj(req.path(), parse_range(&req), parse_conditions(&req))
                     .await
                     .into()
             }
         }
-        "DELETE" => dav.handle_delete(req.path()).await.into(),
+        "DELETE" => dav.handle_delete(req.path(), parse_if(&req)).await.into(),
         "PUT" => dav
             .handle_put(
                 req.path(),
+                parse_if(&req),
                 req.stream().unwrap(),
                 req.headers()
                     .get("content-length")
                     .unwrap()
                     .map_or(0, |v| v.parse::<u64>().unwrap()),
+                parse_conditions(&req),
             )
             .await
             .into(),
@@ -81,6 +123,7 @@ async fn main(mut req: Request, env: Env, _: Context) -> Result<Response> {
                 parse_destination(&req),
                 parse_depth(&req),
                 parse_overwrite(&req),
+                parse_if(&req),
             )
             .await
             .into(),
@@ -90,6 +133,7 @@ async fn main(mut req: Request, env: Env, _: Context) -> Result<Response> {
                 parse_destination(&req),
                 parse_depth(&req),
                 parse_overwrite(&req),
+                parse_if(&req),
             )
             .await
             .into(),
@@ -99,6 +143,9 @@ async fn main(mut req: Request, env: Env, _: Context) -> Result<Response> {
         DavResponseType::DavStreamResponse(r) => {
             r.map_or_else(from_dav_err_response, from_dav_stream_response)
         }
+        DavResponseType::DavBytesResponse(r) => {
+            r.map_or_else(from_dav_err_response, from_dav_bytes_response)
+        }
     };
 
     let cors = Cors::new()
@@ -116,6 +163,13 @@ async fn main(mut req: Request, env: Env, _: Context) -> Result<Response> {
             "overwrite",
             "destination",
             "range",
+            "timeout",
+            "if",
+            "lock-token",
+            "if-match",
+            "if-none-match",
+            "if-modified-since",
+            "if-unmodified-since",
         ])
         .with_exposed_headers([
             "content-length",
@@ -123,26 +177,94 @@ async fn main(mut req: Request, env: Env, _: Context) -> Result<Response> {
             "etag",
             "last-modified",
             "range",
+            "lock-token",
         ]);
     response = response.map(|response| response.with_cors(&cors).unwrap());
     response
 }
 
-fn basic_authorization(
+/// Authorizes a request against either a scoped `Bearer` token or the global
+/// Basic credentials, returning `Some(error response)` to short-circuit with,
+/// or `None` when the request may proceed. `destination` is the target path
+/// of a `COPY`/`MOVE`, which needs its own grant check distinct from `path`
+/// (the source) so a token can't write outside the paths it was scoped to.
+fn authorize(
     authorization_header: Option<String>,
     username: String,
     password: String,
+    bearer_secret: Option<String>,
+    method: &str,
+    path: &str,
+    destination: Option<&str>,
+) -> Option<Result<Response>> {
+    let text = match &authorization_header {
+        Some(text) => text,
+        None => return basic_authorization_error_response(),
+    };
+
+    if let Some(token) = text.strip_prefix("Bearer ") {
+        return bearer_authorization(token, bearer_secret, method, path, destination);
+    }
+
+    basic_authorization(authorization_header, username, password)
+}
+
+fn bearer_authorization(
+    token: &str,
+    bearer_secret: Option<String>,
+    method: &str,
+    path: &str,
+    destination: Option<&str>,
 ) -> Option<Result<Response>> {
-    let basic_authorization_error_response = || {
-        Some(Response::error("Unauthorized", 401).map(|response| {
-            let mut headers = Headers::new();
-            headers
-                .append("WWW-Authenticate", "Basic realm=\"webdav\"")
-                .unwrap();
-            response.with_headers(headers)
-        }))
+    let secret = match bearer_secret {
+        Some(secret) => secret,
+        None => return Some(Response::error("Bearer authentication is not configured", 401)),
     };
 
+    let claims = match verify_bearer_token(token, &secret) {
+        Ok(claims) => claims,
+        Err(message) => return Some(Response::error(message, 401)),
+    };
+
+    if let Some(error) = check_grant(&claims, path, is_write_method(method)) {
+        return Some(error);
+    }
+    if let Some(destination) = destination {
+        if let Some(error) = check_grant(&claims, destination, true) {
+            return Some(error);
+        }
+    }
+
+    None
+}
+
+/// Resolves `path`'s effective grant and maps it onto an error response,
+/// requiring `AccessLevel::Write` when `require_write` is set.
+fn check_grant(claims: &Claims, path: &str, require_write: bool) -> Option<Result<Response>> {
+    match effective_level(claims, path) {
+        None => Some(Response::error("No grant for this path", 403)),
+        Some(AccessLevel::Read) if require_write => {
+            Some(Response::error("Read-only grant", 403))
+        }
+        Some(_) => None,
+    }
+}
+
+fn basic_authorization_error_response() -> Option<Result<Response>> {
+    Some(Response::error("Unauthorized", 401).map(|response| {
+        let mut headers = Headers::new();
+        headers
+            .append("WWW-Authenticate", "Basic realm=\"webdav\"")
+            .unwrap();
+        response.with_headers(headers)
+    }))
+}
+
+fn basic_authorization(
+    authorization_header: Option<String>,
+    username: String,
+    password: String,
+) -> Option<Result<Response>> {
     if let Some(text) = authorization_header {
         let a: Vec<&str> = text.split(" ").collect();
         if a.len() != 2 || a[0] != "Basic" {
@@ -179,28 +301,41 @@ fn parse_depth(req: &Request) -> Depth {
 }
 
 fn parse_range(req: &Request) -> values::Range {
-    req.headers().get("range").unwrap().map_or(
-        values::Range {
-            start: None,
-            end: None,
-        },
-        |v| values::Range::from(v.to_string().split("bytes=").next().unwrap().to_string()),
-    )
+    values::Range::from(req.headers().get("range").unwrap())
 }
 
+/// Extracts the request path from a `Destination:` header, e.g.
+/// `https://host/foo/bar` -> `/foo/bar`, accepting either `http://` or
+/// `https://` (real clients behind Cloudflare TLS send the scheme they
+/// saw). The leading slash is kept since every other path in this
+/// codebase (`req.path()`, lock keys, grant prefixes, R2 object keys) is
+/// `/`-rooted. Returns an empty string, which `handle_copy`/`handle_move`
+/// reject with `400`, if the header is missing or not an absolute URL.
 fn parse_destination(req: &Request) -> String {
     req.headers()
         .get("destination")
         .unwrap()
-        .map_or("".to_string(), |v| {
-            v.split("http://")
-                .nth(1)
-                .unwrap()
-                .split("/")
-                .skip(1)
-                .collect::<Vec<&str>>()
-                .join("/")
+        .and_then(|v| {
+            v.split_once("://").map(|(_, rest)| {
+                rest.split_once('/')
+                    .map_or("".to_string(), |(_, path)| format!("/{}", path))
+            })
         })
+        .unwrap_or_default()
+}
+
+fn parse_if(req: &Request) -> Option<String> {
+    req.headers().get("if").unwrap()
+}
+
+fn parse_conditions(req: &Request) -> Conditions {
+    let headers = req.headers();
+    Conditions::new(
+        headers.get("if-match").unwrap(),
+        headers.get("if-none-match").unwrap(),
+        headers.get("if-modified-since").unwrap(),
+        headers.get("if-unmodified-since").unwrap(),
+    )
 }
 
 fn parse_overwrite(req: &Request) -> Overwrite {
@@ -242,3 +377,68 @@ fn from_dav_stream_response(response: DavStreamResponse) -> Result<Response> {
             .with_status(status_code)
     })
 }
+
+fn from_dav_bytes_response(response: DavBytesResponse) -> Result<Response> {
+    let (status_code, headers, body) = response;
+    console_debug!("{} {:?} {} bytes", status_code, headers, body.len());
+    Response::from_bytes(body).map(|response| {
+        response
+            .with_headers(Headers::from_iter(headers))
+            .with_status(status_code)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use auth::Grant;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    fn sign_token(claims: &Claims, secret: &str) -> String {
+        let payload = serde_json::to_vec(claims).unwrap();
+        let payload_b64 = base64::encode(&payload);
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(payload_b64.as_bytes());
+        let signature_b64 = base64::encode(mac.finalize().into_bytes());
+        format!("{}.{}", payload_b64, signature_b64)
+    }
+
+    #[test]
+    fn bearer_authorization_checks_the_destination_grant_under_its_own_slash_rooted_path() {
+        let claims = Claims {
+            grants: vec![Grant {
+                path_prefix: "/".to_string(),
+                level: AccessLevel::Write,
+            }],
+        };
+        let token = sign_token(&claims, "secret");
+        let result = bearer_authorization(
+            &token,
+            Some("secret".to_string()),
+            "COPY",
+            "/src",
+            Some("/dst"),
+        );
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn bearer_authorization_rejects_a_destination_outside_every_grant() {
+        let claims = Claims {
+            grants: vec![Grant {
+                path_prefix: "/incoming/".to_string(),
+                level: AccessLevel::Write,
+            }],
+        };
+        let token = sign_token(&claims, "secret");
+        let result = bearer_authorization(
+            &token,
+            Some("secret".to_string()),
+            "COPY",
+            "/incoming/src",
+            Some("/outgoing/dst"),
+        );
+        assert!(result.is_some());
+    }
+}
@@ -0,0 +1,164 @@
+use worker::kv::KvStore;
+use worker::{console_debug, Date};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockScope {
+    Exclusive,
+    Shared,
+}
+
+impl From<String> for LockScope {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "shared" => LockScope::Shared,
+            _ => LockScope::Exclusive,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Lock {
+    pub path: String,
+    pub token: String,
+    pub owner: Option<String>,
+    pub depth: String,
+    pub expiry: i64,
+}
+
+/// Write-lock store keyed by resource path, backed by a Workers KV namespace.
+///
+/// R2 has no notion of a lock, so exclusive `LOCK`/`UNLOCK` state lives here
+/// instead, with each entry expiring on its own once the `Timeout` lapses.
+pub struct LockStore {
+    kv: KvStore,
+}
+
+static DEFAULT_TIMEOUT_SECONDS: i64 = 600;
+
+impl LockStore {
+    pub fn new(kv: KvStore) -> LockStore {
+        LockStore { kv }
+    }
+
+    fn key(path: &str) -> String {
+        format!("lock:{}", path)
+    }
+
+    pub fn parse_timeout(header: Option<String>) -> i64 {
+        header
+            .and_then(|v| {
+                v.split(",")
+                    .next()
+                    .map(|v| v.trim().to_string())
+            })
+            .and_then(|v| v.strip_prefix("Second-").map(|v| v.to_string()))
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(DEFAULT_TIMEOUT_SECONDS)
+            .min(DEFAULT_TIMEOUT_SECONDS)
+    }
+
+    /// Fetches the active lock for `path`, treating an expired entry as absent.
+    pub async fn get(&self, path: &str) -> Option<Lock> {
+        let lock: Option<Lock> = self.kv.get(&Self::key(path)).json().await.ok().flatten();
+        match lock {
+            Some(lock) if lock.expiry > Date::now().as_millis() as i64 => Some(lock),
+            Some(_) => {
+                console_debug!("Lazily expiring stale lock on {}", path);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub async fn create(
+        &self,
+        path: String,
+        token: String,
+        owner: Option<String>,
+        depth: String,
+        timeout_seconds: i64,
+    ) -> Result<Lock, String> {
+        let lock = Lock {
+            path: path.clone(),
+            token,
+            owner,
+            depth,
+            expiry: Date::now().as_millis() as i64 + timeout_seconds * 1000,
+        };
+        self.kv
+            .put(&Self::key(&path), &lock)
+            .map_err(|e| e.to_string())?
+            .expiration_ttl(timeout_seconds.max(1) as u64)
+            .execute()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(lock)
+    }
+
+    pub async fn remove(&self, path: &str) -> Result<(), String> {
+        self.kv
+            .delete(&Self::key(path))
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Returns `LockCheck::Unlocked` when `path` is unlocked, `Owned` when the
+    /// caller presented the matching lock token for it, and `Mismatch` or
+    /// `Missing` when it presented another token or none. Used to gate write
+    /// methods. A
+    /// `Depth: infinity` lock on an ancestor collection also covers `path`,
+    /// so ancestors are checked (nearest first) whenever `path` itself
+    /// carries no lock of its own.
+    pub async fn check(&self, path: &str, submitted_token: Option<&str>) -> LockCheck {
+        if let Some(lock) = self.get(path).await {
+            return Self::evaluate(&lock, submitted_token);
+        }
+        for ancestor in ancestors(path) {
+            if let Some(lock) = self.get(&ancestor).await {
+                if lock.depth == "infinity" {
+                    return Self::evaluate(&lock, submitted_token);
+                }
+            }
+        }
+        LockCheck::Unlocked
+    }
+
+    fn evaluate(lock: &Lock, submitted_token: Option<&str>) -> LockCheck {
+        match submitted_token {
+            Some(token) if token_matches(&lock.token, token) => LockCheck::Owned,
+            Some(_) => LockCheck::Mismatch,
+            None => LockCheck::Missing,
+        }
+    }
+}
+
+/// This path's ancestor collection paths, nearest first, e.g.
+/// `/foo/bar/baz.txt` -> `["/foo/bar/", "/foo/", "/"]`.
+fn ancestors(path: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut rest = path.trim_end_matches('/');
+    while let Some(idx) = rest.rfind('/') {
+        rest = &rest[..idx];
+        out.push(format!("{}/", rest));
+    }
+    out
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LockCheck {
+    Unlocked,
+    Owned,
+    Mismatch,
+    Missing,
+}
+
+/// Pulls the `opaquelocktoken:...` value out of an `If: (<...>)` header.
+pub fn parse_if_header(header: &str) -> Option<String> {
+    let start = header.find("(<")? + 2;
+    let end = header[start..].find('>')? + start;
+    Some(header[start..end].to_string())
+}
+
+fn token_matches(stored: &str, submitted: &str) -> bool {
+    stored == submitted
+}
@@ -1,19 +1,174 @@
-use crate::values::{DavProperties, Range};
-use worker::{console_debug, Bucket, ByteStream, FixedLengthStream, Headers, Range as R2Range};
+use crate::values::{Conditions, DavProperties, Range, RangeSpec};
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use uuid::Uuid;
+use worker::{
+    console_debug, Bucket, ByteStream, Conditional as R2Conditional, Date, FixedLengthStream,
+    Range as R2Range,
+};
 
 pub struct R2 {
     bucket: Bucket,
 }
 
+/// Reserved object key holding the JSON-encoded tombstone list `sync_since`
+/// consults, since R2 itself forgets a key the moment it's deleted and a
+/// `sync-collection` REPORT still needs to report it as gone.
+static TOMBSTONE_KEY: &str = ".r2webdav/tombstones";
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct Tombstone {
+    path: String,
+    deleted_at: i64,
+}
+
+/// Resources changed/deleted since some sync point, plus the high-water
+/// mark to encode into the next opaque `sync-token`.
+pub struct SyncChanges {
+    pub changed: Vec<(String, DavProperties)>,
+    pub deleted: Vec<String>,
+    pub high_water_millis: i64,
+}
+
+/// One resolved, satisfiable byte range, buffered in memory so it can be
+/// assembled into a `multipart/byteranges` part.
+pub struct RangePart {
+    pub start: u64,
+    pub end: u64,
+    pub body: Vec<u8>,
+}
+
+pub enum DownloadOutcome {
+    /// No `Range` header (or it was ignored): the whole object, streamed.
+    Full(ByteStream),
+    /// Exactly one satisfiable range: emit as a plain `206`.
+    Single { start: u64, end: u64, stream: ByteStream },
+    /// More than one satisfiable range: emit as `multipart/byteranges`.
+    Multipart { boundary: String, parts: Vec<RangePart> },
+    /// No requested range could be satisfied against the object's length.
+    Unsatisfiable,
+}
+
+/// Outcome of a conditional request: either the object to act on, or a
+/// short-circuit `304`/`412` the caller should return as-is.
+pub enum ConditionalOutcome<T> {
+    Proceed(T),
+    NotModified,
+    PreconditionFailed,
+}
+
+/// Per-property outcome of a PROPPATCH write, bounded by R2's per-object
+/// custom-metadata size limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchOutcome {
+    Applied,
+    TooLarge,
+}
+
+/// Outcome of `copy_object`.
+pub enum CopyOutcome {
+    Created,
+    Overwritten,
+    /// `destination` already existed and the caller passed `overwrite: false`.
+    Conflict,
+}
+
+/// R2 caps the combined size of an object's custom metadata keys and
+/// values at 2048 bytes; reject a PROPPATCH set that would exceed it
+/// instead of letting R2 fail the write outright.
+static MAX_CUSTOM_METADATA_BYTES: usize = 2048;
+
+fn custom_metadata_size(metadata: &HashMap<String, String>) -> usize {
+    metadata.iter().map(|(key, value)| key.len() + value.len()).sum()
+}
+
+/// Maps our header-derived `Conditions` onto R2's native `onlyIf` option.
+fn to_r2_conditional(conditions: &Conditions) -> R2Conditional {
+    R2Conditional {
+        etag_matches: conditions.if_match.clone(),
+        etag_does_not_match: conditions.if_none_match.clone(),
+        uploaded_before: conditions
+            .if_unmodified_since
+            .as_deref()
+            .and_then(Date::parse_rfc1123),
+        uploaded_after: conditions
+            .if_modified_since
+            .as_deref()
+            .and_then(Date::parse_rfc1123),
+    }
+}
+
 impl R2 {
     pub fn new(bucket: Bucket) -> R2 {
         R2 { bucket }
     }
 
-    pub async fn get(&self, path: String) -> Result<(String, DavProperties), String> {
-        match self.bucket.get(path).execute().await {
+    /// `If-None-Match` wins over `If-Modified-Since` when both are present,
+    /// matching actix-web's precedence for conditional GET/HEAD.
+    fn not_modified(properties: &DavProperties, conditions: &Conditions) -> bool {
+        if let Some(none_match) = &conditions.if_none_match {
+            return properties
+                .get_etag
+                .as_deref()
+                .map_or(false, |etag| none_match.iter().any(|v| v == "*" || v == etag));
+        }
+        if let Some(since) = &conditions.if_modified_since {
+            if let (Some(last_modified), Some(since)) =
+                (Date::parse_rfc1123(since), properties.get_last_modified.as_deref().and_then(Date::parse_rfc1123))
+            {
+                return since.as_millis() <= last_modified.as_millis();
+            }
+        }
+        false
+    }
+
+    fn precondition_failed(properties: &DavProperties, conditions: &Conditions) -> bool {
+        if let Some(matches) = &conditions.if_match {
+            if !properties
+                .get_etag
+                .as_deref()
+                .map_or(false, |etag| matches.iter().any(|v| v == "*" || v == etag))
+            {
+                return true;
+            }
+        }
+        if let Some(since) = &conditions.if_unmodified_since {
+            if let (Some(last_modified), Some(since)) = (
+                properties.get_last_modified.as_deref().and_then(Date::parse_rfc1123),
+                Date::parse_rfc1123(since),
+            ) {
+                return last_modified.as_millis() > since.as_millis();
+            }
+        }
+        false
+    }
+
+    pub async fn get(
+        &self,
+        path: String,
+        conditions: Conditions,
+    ) -> Result<ConditionalOutcome<(String, DavProperties, HashMap<String, String>)>, String> {
+        let builder = self.bucket.get(path);
+        let builder = if conditions.is_empty() {
+            builder
+        } else {
+            builder.only_if(to_r2_conditional(&conditions))
+        };
+        match builder.execute().await {
             Ok(f) => f.map_or(Err("Resource not found".to_string()), |file| {
-                Ok((file.key(), DavProperties::from(&file)))
+                let properties = DavProperties::from(&file);
+                if Self::not_modified(&properties, &conditions) {
+                    return Ok(ConditionalOutcome::NotModified);
+                }
+                if Self::precondition_failed(&properties, &conditions) {
+                    return Ok(ConditionalOutcome::PreconditionFailed);
+                }
+                let custom_metadata = file.custom_metadata().unwrap_or_default();
+                Ok(ConditionalOutcome::Proceed((
+                    file.key(),
+                    properties,
+                    custom_metadata,
+                )))
             }),
             Err(error) => Err(error.to_string()),
         }
@@ -33,14 +188,44 @@ impl R2 {
         }
     }
 
-    pub async fn patch_metadata(&self, path: String, metadata: Headers) -> Result<(), String> {
+    /// Applies PROPPATCH `set`/`remove` updates (`None` removes a key,
+    /// already namespace-encoded by the caller) to an object's R2 custom
+    /// metadata, the closest thing R2 has to WebDAV dead properties. Each
+    /// update is tried in turn against a running total, so a property that
+    /// would push the combined set past R2's size limit is rejected
+    /// (`TooLarge`, mapped to `507` at the DAV layer) independently of the
+    /// others, which still commit as `Applied`.
+    pub async fn patch_custom_metadata(
+        &self,
+        path: String,
+        updates: HashMap<String, Option<String>>,
+    ) -> Result<HashMap<String, PatchOutcome>, String> {
         match self.bucket.get(path).execute().await {
-            Ok(f) => f.map_or(Err("Resource not found".to_string()), |file| {
-                match file.write_http_metadata(metadata) {
-                    Ok(_) => Ok(()),
-                    Err(error) => Err(error.to_string()),
+            Ok(Some(file)) => {
+                let mut metadata = file.custom_metadata().unwrap_or_default();
+                let mut outcomes = HashMap::new();
+                for (key, value) in updates {
+                    let mut candidate = metadata.clone();
+                    match &value {
+                        Some(value) => {
+                            candidate.insert(key.clone(), value.clone());
+                        }
+                        None => {
+                            candidate.remove(&key);
+                        }
+                    }
+                    if custom_metadata_size(&candidate) > MAX_CUSTOM_METADATA_BYTES {
+                        outcomes.insert(key, PatchOutcome::TooLarge);
+                        continue;
+                    }
+                    metadata = candidate;
+                    outcomes.insert(key, PatchOutcome::Applied);
                 }
-            }),
+                file.write_custom_metadata(metadata)
+                    .map_err(|e| e.to_string())?;
+                Ok(outcomes)
+            }
+            Ok(None) => Err("Resource not found".to_string()),
             Err(error) => Err(error.to_string()),
         }
     }
@@ -49,41 +234,117 @@ impl R2 {
         &self,
         path: String,
         range: Range,
-    ) -> Result<(DavProperties, ByteStream), String> {
-        let r2range: Option<R2Range> = match (range.start, range.end) {
-            (Some(start), Some(end)) => Some(R2Range::OffsetWithLength {
-                offset: start,
-                length: end - start + 1,
-            }),
-            (Some(start), None) => Some(R2Range::OffsetWithOptionalLength {
-                offset: start,
-                length: None,
-            }),
-            (None, Some(end)) => Some(R2Range::OptionalOffsetWithLength {
-                offset: None,
-                length: end,
-            }),
-            (None, None) => None,
+        conditions: Conditions,
+    ) -> Result<ConditionalOutcome<(DavProperties, DownloadOutcome)>, String> {
+        let builder = self.bucket.get(path.clone());
+        let builder = if conditions.is_empty() {
+            builder
+        } else {
+            builder.only_if(to_r2_conditional(&conditions))
+        };
+        let file = match builder.execute().await {
+            Ok(Some(file)) => file,
+            Ok(None) => return Err("Resource not found".to_string()),
+            Err(error) => return Err(error.to_string()),
         };
-        let path_clone = path.clone();
-        let result = r2range
-            .map_or(self.bucket.get(path), |r| {
-                self.bucket.get(path_clone).range(r)
+        let properties = DavProperties::from(&file);
+        if Self::not_modified(&properties, &conditions) {
+            return Ok(ConditionalOutcome::NotModified);
+        }
+        if Self::precondition_failed(&properties, &conditions) {
+            return Ok(ConditionalOutcome::PreconditionFailed);
+        }
+
+        if range.is_empty() {
+            let stream = Self::body_stream(&file)?;
+            return Ok(ConditionalOutcome::Proceed((
+                properties,
+                DownloadOutcome::Full(stream),
+            )));
+        }
+
+        let len = properties.get_content_length.unwrap_or(0);
+        // `R2Range::OffsetWithLength` only carries `u32` fields, so a range
+        // resolving past `u32::MAX` can't be requested from R2 without
+        // truncating/wrapping into the wrong bytes. Treat it the same as an
+        // unsatisfiable range (416) rather than silently serving garbage.
+        let resolved: Vec<(u64, u64)> = range
+            .specs
+            .iter()
+            .filter_map(|s| s.resolve(len))
+            .filter(|(_, end)| *end <= u32::MAX as u64)
+            .collect();
+        if resolved.is_empty() {
+            return Ok(ConditionalOutcome::Proceed((
+                properties,
+                DownloadOutcome::Unsatisfiable,
+            )));
+        }
+
+        if let [(start, end)] = resolved[..] {
+            let stream = self.ranged_stream(path, start, end).await?;
+            return Ok(ConditionalOutcome::Proceed((
+                properties,
+                DownloadOutcome::Single { start, end, stream },
+            )));
+        }
+
+        let mut parts = Vec::with_capacity(resolved.len());
+        for (start, end) in resolved {
+            let stream = self.ranged_stream(path.clone(), start, end).await?;
+            let body = Self::read_all(stream).await?;
+            parts.push(RangePart { start, end, body });
+        }
+        let boundary = format!("r2webdav-{}", Uuid::new_v4());
+        Ok(ConditionalOutcome::Proceed((
+            properties,
+            DownloadOutcome::Multipart { boundary, parts },
+        )))
+    }
+
+    fn body_stream(file: &worker::Object) -> Result<ByteStream, String> {
+        file.body()
+            .ok_or_else(|| "Failed to get file body stream".to_string())?
+            .stream()
+            .map_err(|_| "Failed to get file body stream".to_string())
+    }
+
+    async fn ranged_stream(&self, path: String, start: u64, end: u64) -> Result<ByteStream, String> {
+        let file = self
+            .bucket
+            .get(path)
+            .range(R2Range::OffsetWithLength {
+                offset: start as u32,
+                length: (end - start + 1) as u32,
             })
             .execute()
-            .await;
-        match result {
-            Ok(f) => f.map_or(Err("Resource not found".to_string()), |file| {
-                file.body()
-                    .map_or(Err("Failed to get file body stream".to_string()), |b| {
-                        b.stream().map_or(
-                            Err("Failed to get file body stream".to_string()),
-                            |stream| Ok((DavProperties::from(&file), stream)),
-                        )
-                    })
-            }),
-            Err(error) => Err(error.to_string()),
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| "Resource not found".to_string())?;
+        Self::body_stream(&file)
+    }
+
+    async fn read_all(mut stream: ByteStream) -> Result<Vec<u8>, String> {
+        let mut buf = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            buf.extend_from_slice(&chunk.map_err(|e| e.to_string())?);
         }
+        Ok(buf)
+    }
+
+    /// Reads an object's full body as UTF-8 text, for formats like iCalendar
+    /// that callers need to parse rather than stream straight to a client.
+    pub async fn read_to_string(&self, path: String) -> Result<String, String> {
+        let file = self
+            .bucket
+            .get(path)
+            .execute()
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| "Resource not found".to_string())?;
+        let stream = Self::body_stream(&file)?;
+        let bytes = Self::read_all(stream).await?;
+        String::from_utf8(bytes).map_err(|e| e.to_string())
     }
 
     pub async fn delete(&self, path: String) -> Result<(), String> {
@@ -93,20 +354,230 @@ impl R2 {
         }
     }
 
+    /// Copies `source` onto `destination` by reading its full body and
+    /// writing it back under the new key, since R2 has no native copy
+    /// operation. Carries over `source`'s HTTP metadata (e.g. Content-Type)
+    /// and custom metadata (the dead properties PROPPATCH persisted), so a
+    /// COPY/MOVE doesn't silently drop them. Declines with `Conflict` when
+    /// `destination` already exists and `overwrite` is `false`, per RFC 4918
+    /// §9.8.4/§9.9.3.
+    pub async fn copy_object(
+        &self,
+        source: String,
+        destination: String,
+        overwrite: bool,
+    ) -> Result<CopyOutcome, String> {
+        let existed = self
+            .bucket
+            .get(destination.clone())
+            .execute()
+            .await
+            .map_err(|e| e.to_string())?
+            .is_some();
+        if existed && !overwrite {
+            return Ok(CopyOutcome::Conflict);
+        }
+
+        let file = self
+            .bucket
+            .get(source)
+            .execute()
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| "Resource not found".to_string())?;
+        let content_length = file.size() as u64;
+        let http_metadata = file.http_metadata();
+        let custom_metadata = file.custom_metadata().unwrap_or_default();
+        let stream = Self::body_stream(&file)?;
+        self.bucket
+            .put(destination, FixedLengthStream::wrap(stream, content_length))
+            .http_metadata(http_metadata)
+            .custom_metadata(custom_metadata)
+            .execute()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(if existed {
+            CopyOutcome::Overwritten
+        } else {
+            CopyOutcome::Created
+        })
+    }
+
+    /// Records `path` as deleted "now", so a later `sync-collection` REPORT
+    /// whose token predates this moment reports it as a `404` response.
+    pub async fn record_tombstone(&self, path: String) -> Result<(), String> {
+        let mut tombstones = self.read_tombstones().await?;
+        tombstones.push(Tombstone {
+            path,
+            deleted_at: Date::now().as_millis() as i64,
+        });
+        self.write_tombstones(&tombstones).await
+    }
+
+    async fn read_tombstones(&self) -> Result<Vec<Tombstone>, String> {
+        let file = match self
+            .bucket
+            .get(TOMBSTONE_KEY.to_string())
+            .execute()
+            .await
+            .map_err(|e| e.to_string())?
+        {
+            Some(file) => file,
+            None => return Ok(Vec::new()),
+        };
+        let stream = Self::body_stream(&file)?;
+        let bytes = Self::read_all(stream).await?;
+        serde_json::from_slice(&bytes).map_err(|e| e.to_string())
+    }
+
+    async fn write_tombstones(&self, tombstones: &[Tombstone]) -> Result<(), String> {
+        let bytes = serde_json::to_vec(tombstones).map_err(|e| e.to_string())?;
+        self.bucket
+            .put(TOMBSTONE_KEY.to_string(), bytes)
+            .execute()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Resolves a `sync-collection` REPORT against `since_millis` (0 for a
+    /// client's first sync): resources under `prefix` uploaded after that
+    /// point, tombstones recorded after it, and the new high-water mark to
+    /// encode as the next `sync-token`. The bucket serves many independent
+    /// collections, so only tombstones under `prefix` are ever pruned here,
+    /// and only once they're at or before this call's own `since_millis` —
+    /// an unrelated collection's tombstones are left untouched so its own
+    /// sync-collection REPORTs still see them.
+    pub async fn sync_since(&self, prefix: String, since_millis: i64) -> Result<SyncChanges, String> {
+        let files = self
+            .bucket
+            .list()
+            .prefix(prefix.clone())
+            .execute()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut changed = Vec::new();
+        let mut high_water = since_millis;
+        for file in files.objects() {
+            let millis = file.uploaded().as_millis() as i64;
+            high_water = high_water.max(millis);
+            if millis > since_millis {
+                changed.push((file.key(), DavProperties::from(&file)));
+            }
+        }
+
+        let tombstones = self.read_tombstones().await?;
+        let mut deleted = Vec::new();
+        let mut retained = Vec::new();
+        for tombstone in tombstones {
+            if !tombstone.path.starts_with(&prefix) {
+                retained.push(tombstone);
+                continue;
+            }
+            if tombstone.deleted_at <= since_millis {
+                continue;
+            }
+            high_water = high_water.max(tombstone.deleted_at);
+            deleted.push(tombstone.path.clone());
+            retained.push(tombstone);
+        }
+        self.write_tombstones(&retained).await?;
+
+        Ok(SyncChanges {
+            changed,
+            deleted,
+            high_water_millis: high_water,
+        })
+    }
+
     pub async fn put(
         &self,
         path: String,
         stream: ByteStream,
         content_length: u64,
+        conditions: Conditions,
+    ) -> Result<ConditionalOutcome<DavProperties>, String> {
+        let builder = self
+            .bucket
+            .put(path, FixedLengthStream::wrap(stream, content_length));
+        let builder = if conditions.is_empty() {
+            builder
+        } else {
+            builder.only_if(to_r2_conditional(&conditions))
+        };
+        match builder.execute().await {
+            Ok(Some(file)) => Ok(ConditionalOutcome::Proceed(DavProperties::from(&file))),
+            Ok(None) => Ok(ConditionalOutcome::PreconditionFailed),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    /// Uploads `stream` as a series of R2 multipart parts instead of a
+    /// single `bucket.put`, so bodies larger than a Worker's per-request
+    /// size ceiling can still be stored. R2's conditional `onlyIf` option
+    /// isn't exposed on multipart uploads, so this path ignores `conditions`.
+    pub async fn put_multipart(
+        &self,
+        path: String,
+        mut stream: ByteStream,
     ) -> Result<DavProperties, String> {
-        match self
+        let upload = self
             .bucket
-            .put(path, FixedLengthStream::wrap(stream, content_length))
+            .create_multipart_upload(path)
             .execute()
             .await
-        {
+            .map_err(|e| e.to_string())?;
+
+        let mut buffer: Vec<u8> = Vec::with_capacity(MULTIPART_PART_SIZE);
+        let mut part_number: u16 = 1;
+        let mut parts = Vec::new();
+
+        let result: Result<(), String> = async {
+            while let Some(chunk) = stream.next().await {
+                buffer.extend_from_slice(&chunk.map_err(|e| e.to_string())?);
+                while buffer.len() >= MULTIPART_PART_SIZE {
+                    let part = buffer.drain(..MULTIPART_PART_SIZE).collect::<Vec<u8>>();
+                    parts.push(
+                        upload
+                            .upload_part(part_number, part)
+                            .await
+                            .map_err(|e| e.to_string())?,
+                    );
+                    part_number += 1;
+                }
+            }
+            if !buffer.is_empty() {
+                parts.push(
+                    upload
+                        .upload_part(part_number, buffer.clone())
+                        .await
+                        .map_err(|e| e.to_string())?,
+                );
+            }
+            Ok(())
+        }
+        .await;
+
+        if let Err(message) = result {
+            let _ = upload.abort().await;
+            return Err(message);
+        }
+
+        match upload.complete(parts).await {
             Ok(file) => Ok(DavProperties::from(&file)),
-            Err(error) => Err(error.to_string()),
+            Err(error) => {
+                let _ = upload.abort().await;
+                Err(error.to_string())
+            }
         }
     }
 }
+
+/// Size of each part `put_multipart` uploads; only the final part may be
+/// smaller. Bodies whose `content-length` is at or above
+/// `MULTIPART_THRESHOLD` are streamed through `put_multipart` instead of one
+/// `bucket.put`.
+pub static MULTIPART_PART_SIZE: usize = 8 * 1024 * 1024;
+pub static MULTIPART_THRESHOLD: u64 = 100 * 1024 * 1024;
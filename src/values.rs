@@ -19,19 +19,71 @@ impl From<String> for Depth {
     }
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Hash, Eq)]
+/// A single `bytes=` range as written on the wire, per RFC 7233. Offsets are
+/// `u64` so a range into a multi-gigabyte object (see `R2::put_multipart`)
+/// parses and resolves correctly instead of silently failing past
+/// `u32::MAX`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeSpec {
+    FromTo(u64, u64),
+    From(u64),
+    Suffix(u64),
+}
+
+impl RangeSpec {
+    /// Clamps this spec against a resource of `len` bytes, returning the
+    /// resolved inclusive `(start, end)` byte offsets, or `None` if it
+    /// cannot be satisfied (e.g. a zero-length suffix, or a start past the
+    /// end). A suffix longer than the resource resolves to the whole of it.
+    pub fn resolve(&self, len: u64) -> Option<(u64, u64)> {
+        if len == 0 {
+            return None;
+        }
+        match self {
+            RangeSpec::FromTo(start, end) => {
+                let (start, end) = (*start, *end);
+                if start >= len {
+                    None
+                } else {
+                    Some((start, end.min(len - 1)))
+                }
+            }
+            RangeSpec::From(start) => {
+                let start = *start;
+                if start >= len {
+                    None
+                } else {
+                    Some((start, len - 1))
+                }
+            }
+            RangeSpec::Suffix(suffix) => {
+                let suffix = (*suffix).min(len);
+                if suffix == 0 {
+                    None
+                } else {
+                    Some((len - suffix, len - 1))
+                }
+            }
+        }
+    }
+}
+
+/// The full `Range:` header: zero or more specs, parsed from `bytes=...`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct Range {
-    pub start: Option<u32>,
-    pub end: Option<u32>,
+    pub specs: Vec<RangeSpec>,
+}
+
+impl Range {
+    pub fn is_empty(&self) -> bool {
+        self.specs.is_empty()
+    }
 }
 
 impl From<Option<String>> for Range {
     fn from(line: Option<String>) -> Self {
         match line {
-            None => Range {
-                start: None,
-                end: None,
-            },
+            None => Range::default(),
             Some(line) => Range::from(line),
         }
     }
@@ -39,26 +91,30 @@ impl From<Option<String>> for Range {
 
 impl From<String> for Range {
     fn from(line: String) -> Self {
-        if line.contains(";") {
-            return Range {
-                start: None,
-                end: None,
-            };
+        match line.strip_prefix("bytes=") {
+            None => Range::default(),
+            Some(rest) => Range {
+                specs: rest.split(',').filter_map(parse_range_spec).collect(),
+            },
         }
+    }
+}
 
-        line.split("-")
-            .map(|v| v.parse::<u32>())
-            .collect::<Result<Vec<u32>, _>>()
-            .map_or(Range::from(None), |v| match v.len() {
-                2 => Range {
-                    start: Some(v[0]),
-                    end: Some(v[1]),
-                },
-                _ => Range {
-                    start: None,
-                    end: None,
-                },
-            })
+fn parse_range_spec(part: &str) -> Option<RangeSpec> {
+    let (start, end) = part.trim().split_once('-')?;
+    match (start.trim(), end.trim()) {
+        ("", "") => None,
+        ("", suffix) => suffix.parse::<u64>().ok().map(RangeSpec::Suffix),
+        (start, "") => start.parse::<u64>().ok().map(RangeSpec::From),
+        (start, end) => {
+            let start = start.parse::<u64>().ok()?;
+            let end = end.parse::<u64>().ok()?;
+            if start > end {
+                None
+            } else {
+                Some(RangeSpec::FromTo(start, end))
+            }
+        }
     }
 }
 
@@ -79,6 +135,48 @@ impl From<String> for Overwrite {
     }
 }
 
+/// Validators lifted from the `If-*` request headers, threaded down into
+/// `R2::get`/`download`/`put` so they can be mapped onto R2's conditional
+/// `onlyIf` options; `get`/`download` also check them against the fetched
+/// object's properties to choose between `304` and `412`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Conditions {
+    pub if_match: Option<Vec<String>>,
+    pub if_none_match: Option<Vec<String>>,
+    pub if_modified_since: Option<String>,
+    pub if_unmodified_since: Option<String>,
+}
+
+impl Conditions {
+    pub fn new(
+        if_match: Option<String>,
+        if_none_match: Option<String>,
+        if_modified_since: Option<String>,
+        if_unmodified_since: Option<String>,
+    ) -> Conditions {
+        Conditions {
+            if_match: if_match.map(|v| split_etags(&v)),
+            if_none_match: if_none_match.map(|v| split_etags(&v)),
+            if_modified_since,
+            if_unmodified_since,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.if_match.is_none()
+            && self.if_none_match.is_none()
+            && self.if_modified_since.is_none()
+            && self.if_unmodified_since.is_none()
+    }
+}
+
+fn split_etags(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|v| v.trim().trim_matches('"').to_string())
+        .collect()
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Hash, Eq)]
 pub struct DavProperties {
     pub creation_date: Option<String>,
@@ -102,7 +200,80 @@ impl From<&Object> for DavProperties {
             get_content_length: Some(file.size().into()),
             get_content_type: http_metedata.content_type,
             get_etag: Some(file.http_etag()),
-            get_last_modified: None,
+            get_last_modified: Some(to_rfc1123(&file.uploaded())),
         }
     }
 }
+
+static WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+static MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Renders a `worker::Date` as an RFC 1123 timestamp, e.g.
+/// `Tue, 15 Nov 1994 08:12:31 GMT`, as required for `Last-Modified`.
+fn to_rfc1123(date: &worker::Date) -> String {
+    let millis = date.as_millis() as i64;
+    let days = millis.div_euclid(86_400_000);
+    let secs_of_day = millis.rem_euclid(86_400_000) / 1000;
+    let (hour, minute, second) = (secs_of_day / 3600, (secs_of_day / 60) % 60, secs_of_day % 60);
+    let weekday = WEEKDAYS[((days % 7 + 7 + 4) % 7) as usize];
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        weekday, day, MONTHS[(month - 1) as usize], year, hour, minute, second
+    )
+}
+
+/// Howard Hinnant's days-from-civil algorithm, inverted: converts a count of
+/// days since the Unix epoch into a `(year, month, day)` triple.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = (z - era * 146097) as u64;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let y = yoe as i64 + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_spec_from_to_clamps_to_content_length() {
+        assert_eq!(RangeSpec::FromTo(0, 99).resolve(50), Some((0, 49)));
+        assert_eq!(RangeSpec::FromTo(100, 200).resolve(50), None);
+    }
+
+    #[test]
+    fn range_spec_suffix_clamps_to_content_length() {
+        assert_eq!(RangeSpec::Suffix(10).resolve(5), Some((0, 4)));
+        assert_eq!(RangeSpec::Suffix(0).resolve(100), None);
+    }
+
+    #[test]
+    fn range_spec_resolves_offsets_past_u32_max() {
+        let len = 6_000_000_000u64;
+        let start = 5_000_000_000u64;
+        assert_eq!(RangeSpec::From(start).resolve(len), Some((start, len - 1)));
+    }
+
+    #[test]
+    fn parse_range_spec_parses_offsets_past_u32_max() {
+        assert_eq!(
+            parse_range_spec("5000000000-"),
+            Some(RangeSpec::From(5_000_000_000))
+        );
+    }
+
+    #[test]
+    fn range_from_header_with_large_offset_is_not_empty() {
+        let range = Range::from("bytes=5000000000-".to_string());
+        assert!(!range.is_empty());
+    }
+}
@@ -2,6 +2,11 @@ use quick_xml::events::Event;
 use quick_xml::reader::Reader;
 use std::collections::HashMap;
 
+/// Strips any `prefix:` off an element name, e.g. `D:prop` -> `prop`.
+pub fn local_name(name: &str) -> &str {
+    name.split(':').last().unwrap_or(name)
+}
+
 #[derive(Default, Debug, Clone)]
 pub struct XMLNode {
     pub name: String,
@@ -39,6 +44,32 @@ impl XMLNode {
         self.elements.push(element);
     }
 
+    /// Finds the first direct child whose tag name matches `name`, ignoring
+    /// any namespace prefix (e.g. `find_child("prop")` matches `D:prop`).
+    pub fn find_child(&self, name: &str) -> Option<&XMLNode> {
+        self.elements.iter().find(|e| local_name(&e.name) == name)
+    }
+
+    /// All direct children whose tag name matches `name`, ignoring prefix.
+    pub fn find_children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a XMLNode> {
+        self.elements.iter().filter(move |e| local_name(&e.name) == name)
+    }
+
+    /// This element's tag name with any namespace prefix stripped.
+    pub fn local_name(&self) -> &str {
+        local_name(&self.name)
+    }
+
+    /// Looks up an attribute by its (unprefixed) local name.
+    pub fn attr(&self, name: &str) -> Option<&String> {
+        self.attributes.as_ref().and_then(|attrs| {
+            attrs
+                .iter()
+                .find(|(key, _)| local_name(key) == name)
+                .map(|(_, value)| value)
+        })
+    }
+
     pub fn build(&self) -> String {
         let mut xml = Vec::new();
         xml.push("<?xml version=\"1.0\" encoding=\"utf-8\"?>".to_string());
@@ -73,48 +104,42 @@ impl XMLNode {
         xml.join("")
     }
 
+    /// Parses `xml` into a tree around an explicit stack of in-progress
+    /// nodes: `Start` pushes a new node, `Text`/`CData` set the top node's
+    /// value, `Empty` (self-closing tags like `<D:prop/>`) attaches a
+    /// childless node directly, and `End` pops the top node onto its new
+    /// parent (or records it as the root once the stack empties).
     pub fn parse_xml(xml: &str) -> Result<XMLNode, String> {
         let mut reader = Reader::from_str(xml);
         reader.trim_text(true);
         let mut buf = Vec::new();
-        let mut elements: Vec<XMLNode> = Vec::new();
-        let mut stack: Vec<(String, HashMap<String, String>, String)> = Vec::new();
+        let mut stack: Vec<XMLNode> = Vec::new();
+        let mut root: Option<XMLNode> = None;
+
         loop {
             match reader.read_event_into(&mut buf) {
                 Ok(Event::Start(ref e)) => {
-                    stack.push((
-                        std::str::from_utf8(e.name().as_ref()).unwrap().to_string(),
-                        e.attributes()
-                            .map(|a| {
-                                let a = a.unwrap();
-                                (
-                                    std::str::from_utf8(a.key.as_ref()).unwrap().to_string(),
-                                    std::str::from_utf8(a.value.as_ref()).unwrap().to_string(),
-                                )
-                            })
-                            .collect(),
-                        "".to_string(),
-                    ));
+                    stack.push(XMLNode::new(tag_name(e.name().as_ref()), Some(attrs(e)), None));
+                }
+                Ok(Event::Empty(ref e)) => {
+                    let node = XMLNode::new(tag_name(e.name().as_ref()), Some(attrs(e)), None);
+                    attach(&mut stack, &mut root, node);
                 }
                 Ok(Event::End(_)) => {
-                    stack.pop().map(|(name, attributes, value)| {
-                        let mut element =
-                            XMLNode::new(name, Some(attributes.into_iter().collect()), Some(value));
-                        match elements.pop() {
-                            None => {
-                                let _ = &elements.push(element.clone());
-                            }
-                            Some(c) => {
-                                element.add(c);
-                                let _ = &elements.push(element);
-                            }
-                        };
-                    });
+                    let node = stack
+                        .pop()
+                        .ok_or_else(|| "Unbalanced closing tag".to_string())?;
+                    attach(&mut stack, &mut root, node);
                 }
                 Ok(Event::Text(e)) => {
-                    stack.pop().map(|(name, attributes, _)| {
-                        stack.push((name, attributes, e.unescape().unwrap().into_owned()));
-                    });
+                    if let Some(node) = stack.last_mut() {
+                        node.value = Some(e.unescape().map_err(|e| e.to_string())?.into_owned());
+                    }
+                }
+                Ok(Event::CData(e)) => {
+                    if let Some(node) = stack.last_mut() {
+                        node.value = Some(String::from_utf8_lossy(e.as_ref()).into_owned());
+                    }
                 }
                 Ok(Event::Eof) => break,
                 Err(e) => {
@@ -128,14 +153,36 @@ impl XMLNode {
             }
             buf.clear();
         }
-        if elements.len() == 1 {
-            Ok(elements.pop().unwrap())
-        } else {
-            Err(format!("XMLNode parse error, {:?}", elements))
-        }
+
+        root.ok_or_else(|| "XMLNode parse error: no root element".to_string())
     }
 }
 
+/// Attaches a just-closed node to the node now on top of the stack, or
+/// records it as the document root once the stack has emptied.
+fn attach(stack: &mut Vec<XMLNode>, root: &mut Option<XMLNode>, node: XMLNode) {
+    match stack.last_mut() {
+        Some(parent) => parent.add(node),
+        None => *root = Some(node),
+    }
+}
+
+fn tag_name(raw: &[u8]) -> String {
+    std::str::from_utf8(raw).unwrap().to_string()
+}
+
+fn attrs(e: &quick_xml::events::BytesStart) -> Vec<(String, String)> {
+    e.attributes()
+        .filter_map(|a| a.ok())
+        .map(|a| {
+            (
+                std::str::from_utf8(a.key.as_ref()).unwrap().to_string(),
+                std::str::from_utf8(a.value.as_ref()).unwrap().to_string(),
+            )
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use crate::xml::XMLNode;
@@ -159,4 +206,15 @@ mod tests {
         let xml = XMLNode::parse_xml(xml).unwrap();
         assert!(xml.build() == "<?xml version=\"1.0\" encoding=\"utf-8\"?><root><child><grandchild><greatgrandchild>value</greatgrandchild></grandchild></child></root>", "{}", xml.build())
     }
+
+    #[test]
+    fn xml_parse_siblings_and_self_closing() {
+        let xml = "<D:propfind xmlns:D=\"DAV:\"><D:prop><D:displayname/><D:getetag/></D:prop></D:propfind>";
+        let xml = XMLNode::parse_xml(xml).unwrap();
+        assert_eq!(xml.local_name(), "propfind");
+        let prop = xml.find_child("prop").unwrap();
+        assert_eq!(prop.elements.len(), 2);
+        assert_eq!(prop.elements[0].local_name(), "displayname");
+        assert_eq!(prop.elements[1].local_name(), "getetag");
+    }
 }